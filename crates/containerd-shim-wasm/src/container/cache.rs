@@ -0,0 +1,220 @@
+//! An in-memory cache of compiled modules, shared between the containers of a shim process.
+//!
+//! In daemon mode (see `sandbox::manager`) a single process runs the containers of many pods,
+//! so pods running the same image can reuse the module compiled for the first one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::container::Engine;
+use crate::sandbox::oci::WasmLayer;
+
+/// Default capacity of a [`ModuleCache`], in bytes.
+pub const DEFAULT_MODULE_CACHE_CAPACITY: usize = 512 * 1024 * 1024;
+
+/// The key of a compiled module in a [`ModuleCache`].
+///
+/// A layer is identified by the digest of its original (not precompiled) content,
+/// together with the `can_precompile` key of the engine that compiled it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ModuleCacheKey {
+    pub digest: String,
+    pub precompile_key: Option<String>,
+}
+
+impl ModuleCacheKey {
+    pub fn new(engine: &impl Engine, layer: &WasmLayer) -> Self {
+        Self {
+            digest: layer.config.digest().clone(),
+            precompile_key: engine.can_precompile(),
+        }
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    size: usize,
+    last_used: u64,
+}
+
+struct Inner<T> {
+    entries: HashMap<ModuleCacheKey, Entry<T>>,
+    size: usize,
+    clock: u64,
+}
+
+/// ModuleCache is a size bounded cache of compiled modules.
+///
+/// Clones of a cache share the same entries. When the total size of the entries goes over
+/// the capacity, the least recently used entries are evicted.
+/// The size of an entry is provided by the caller, usually the size of the layer it was compiled from.
+pub struct ModuleCache<T: Clone> {
+    inner: Arc<Mutex<Inner<T>>>,
+    capacity: usize,
+}
+
+impl<T: Clone> Clone for ModuleCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<T: Clone> Default for ModuleCache<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MODULE_CACHE_CAPACITY)
+    }
+}
+
+impl<T: Clone> ModuleCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        let inner = Inner {
+            entries: HashMap::new(),
+            size: 0,
+            clock: 0,
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &ModuleCacheKey) -> Option<T> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(key)?;
+        entry.last_used = clock;
+        Some(entry.value.clone())
+    }
+
+    /// Inserts a value in the cache, evicting the least recently used entries to make room for it.
+    /// Values bigger than the capacity of the cache are not stored.
+    pub fn insert(&self, key: ModuleCacheKey, value: T, size: usize) {
+        if size > self.capacity {
+            log::debug!("module {key:?} is too big for the cache");
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let last_used = inner.clock;
+        let entry = Entry {
+            value,
+            size,
+            last_used,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.size -= old.size;
+        }
+        inner.size += size;
+
+        while inner.size > self.capacity {
+            let Some(lru) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            log::debug!("evicting module {lru:?} from the cache");
+            let entry = inner.entries.remove(&lru).unwrap();
+            inner.size -= entry.size;
+        }
+    }
+
+    /// Returns the cached value for `key`, or compiles it with `f` and caches the result.
+    /// The cache is not locked while `f` runs, so concurrent misses on the same key may compile twice.
+    pub fn get_or_try_insert_with(
+        &self,
+        key: ModuleCacheKey,
+        size: usize,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        if let Some(value) = self.get(&key) {
+            log::debug!("using cached module {key:?}");
+            return Ok(value);
+        }
+        let value = f()?;
+        self.insert(key, value.clone(), size);
+        Ok(value)
+    }
+
+    /// Returns the total size of the cached entries.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(digest: &str) -> ModuleCacheKey {
+        ModuleCacheKey {
+            digest: digest.to_string(),
+            precompile_key: Some("key".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_cache_is_shared_between_clones() {
+        let cache = ModuleCache::new(100);
+        let clone = cache.clone();
+
+        cache.insert(key("a"), 1, 10);
+        assert_eq!(clone.get(&key("a")), Some(1));
+        assert_eq!(clone.size(), 10);
+
+        let other_key = ModuleCacheKey {
+            precompile_key: Some("other".to_string()),
+            ..key("a")
+        };
+        assert_eq!(clone.get(&other_key), None);
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache = ModuleCache::new(30);
+        cache.insert(key("a"), 1, 10);
+        cache.insert(key("b"), 2, 10);
+        cache.insert(key("c"), 3, 10);
+
+        // touch "a" so that "b" is the least recently used
+        assert_eq!(cache.get(&key("a")), Some(1));
+
+        cache.insert(key("d"), 4, 10);
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")), Some(1));
+        assert_eq!(cache.get(&key("c")), Some(3));
+        assert_eq!(cache.get(&key("d")), Some(4));
+        assert_eq!(cache.size(), 30);
+
+        // too big to be cached
+        cache.insert(key("e"), 5, 31);
+        assert_eq!(cache.get(&key("e")), None);
+        assert_eq!(cache.size(), 30);
+    }
+
+    #[test]
+    fn test_get_or_try_insert_with() -> Result<()> {
+        let cache = ModuleCache::new(100);
+
+        let value = cache.get_or_try_insert_with(key("a"), 10, || Ok(1))?;
+        assert_eq!(value, 1);
+
+        let value = cache.get_or_try_insert_with(key("a"), 10, || panic!("should be cached"))?;
+        assert_eq!(value, 1);
+
+        let result = cache.get_or_try_insert_with(key("b"), 10, || anyhow::bail!("failed"));
+        assert!(result.is_err());
+        assert_eq!(cache.get(&key("b")), None);
+
+        Ok(())
+    }
+}
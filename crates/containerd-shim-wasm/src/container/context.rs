@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context};
@@ -31,6 +32,11 @@ pub trait RuntimeContext {
     // the platform for the container using the struct defined on the OCI spec definition
     // https://github.com/opencontainers/image-spec/blob/v1.1.0-rc5/image-index.md
     fn platform(&self) -> &Platform;
+
+    // ctx.annotations() returns the annotations of the runtime spec, if any
+    fn annotations(&self) -> Option<&HashMap<String, String>> {
+        None
+    }
 }
 
 /// The source for a WASI module / components.
@@ -58,7 +64,7 @@ impl<'a> Source<'a> {
                     .context("module not found")?;
                 Ok(Cow::Owned(std::fs::read(path)?))
             }
-            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer)),
+            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer[..])),
             Source::Oci(_modules) => {
                 bail!("only a single module is supported when using images with OCI layers")
             }
@@ -120,6 +126,10 @@ impl RuntimeContext for WasiContext<'_> {
     fn platform(&self) -> &Platform {
         self.platform
     }
+
+    fn annotations(&self) -> Option<&HashMap<String, String>> {
+        self.spec.annotations().as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -357,7 +367,7 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[WasmLayer {
-                layer: vec![],
+                layer: vec![].into(),
                 config: Descriptor::new(oci_spec::image::MediaType::Other("".to_string()), 10, ""),
             }],
             platform: &Platform::default(),
@@ -367,4 +377,23 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_annotations() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .annotations(HashMap::from([("key".to_string(), "value".to_string())]))
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+        };
+
+        let annotations = ctx.annotations().expect("annotations are set");
+        assert_eq!(annotations.get("key").map(String::as_str), Some("value"));
+
+        Ok(())
+    }
 }
@@ -4,7 +4,7 @@ use std::io::Read;
 use anyhow::{bail, Context, Result};
 
 use super::Source;
-use crate::container::{PathResolve, RuntimeContext};
+use crate::container::{PathResolve, RuntimeContext, WasiVersion, WasmBinaryType};
 use crate::sandbox::oci::WasmLayer;
 use crate::sandbox::Stdio;
 
@@ -21,12 +21,30 @@ pub trait Engine: Clone + Send + Sync + 'static {
     /// * a OCI image with wasm layers
     /// * a file with the `wasm` filetype header
     /// * a parsable `wat` file.
+    ///
+    /// For OCI images it also checks that the engine supports the wasm platform of the image,
+    /// and that the wasm layers can run on it (see [`WasiVersion::check_binary`]).
     fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
         let source = ctx.entrypoint().source;
 
         let path = match source {
             Source::File(path) => path,
-            Source::Oci(_) => return Ok(()),
+            Source::Oci(layers) => {
+                let Some(version) = WasiVersion::from_platform(ctx.platform()) else {
+                    return Ok(());
+                };
+                if !Self::supported_platforms().contains(&version.os()) {
+                    bail!("{} doesn't support the {version} platform", Self::name());
+                }
+                for layer in layers {
+                    if let Some(binary) = WasmBinaryType::from_bytes(&layer.layer) {
+                        version
+                            .check_binary(binary, layer.config.media_type())
+                            .with_context(|| format!("layer {}", layer.config.digest()))?;
+                    }
+                }
+                return Ok(());
+            }
         };
 
         path.resolve_in_path_or_cwd()
@@ -44,6 +62,15 @@ pub trait Engine: Clone + Send + Sync + 'static {
         Ok(())
     }
 
+    /// Prepare the OCI layers of a container before it's started.
+    /// This is called in the shim process before the container process is created, so anything the engine
+    /// keeps in memory here, like a [`ModuleCache`](crate::container::ModuleCache) of compiled modules,
+    /// is inherited by the container and reused by the following containers of the same shim.
+    /// Errors are logged and the container falls back to loading the layers itself.
+    fn prepare(&self, _layers: &[WasmLayer]) -> Result<()> {
+        Ok(())
+    }
+
     /// Return the supported OCI layer types
     /// This is used to filter only layers that are supported by the runtime.
     /// The default implementation returns the OCI layer type 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
@@ -54,6 +81,15 @@ pub trait Engine: Clone + Send + Sync + 'static {
         &["application/vnd.bytecodealliance.wasm.component.layer.v0+wasm"]
     }
 
+    /// Return the supported wasm platforms, in order of preference.
+    /// A wasm platform is the `os` of an OCI platform with the `wasm` architecture, e.g. `wasip1` or `wasip2`.
+    /// When the image is an index with manifests for several platforms, the manifest of the first platform
+    /// in this list is used, and its platform is available to the engine with [`RuntimeContext::platform`].
+    /// The default implementation returns `wasip1`.
+    fn supported_platforms() -> &'static [&'static str] {
+        &["wasip1"]
+    }
+
     /// Precompile passes supported OCI layers to engine for compilation
     /// This is used to precompile the layers before they are run and will be called if `can_precompile` returns `true`.
     /// It is called only the first time a module is run and the resulting bytes will be cached in the containerd content store.  
@@ -78,4 +114,11 @@ pub trait Engine: Clone + Send + Sync + 'static {
     fn can_precompile(&self) -> Option<String> {
         None
     }
+
+    /// Information about the engine printed with the `--version` of the shim, as `(name, value)` pairs,
+    /// e.g. the version of the runtime or the plugins it loaded.
+    /// The default implementation returns nothing.
+    fn info(&self) -> Vec<(String, String)> {
+        vec![]
+    }
 }
@@ -10,17 +10,19 @@
 //! * Less customizable
 //! * Currently only works on Linux
 
+mod cache;
 mod context;
 mod engine;
 mod path;
 mod wasm;
 
+pub use cache::{ModuleCache, ModuleCacheKey, DEFAULT_MODULE_CACHE_CAPACITY};
 pub(crate) use context::WasiContext;
 pub use context::{Entrypoint, RuntimeContext, Source};
 pub use engine::Engine;
 pub use instance::Instance;
 pub use path::PathResolve;
-pub use wasm::WasmBinaryType;
+pub use wasm::{ComponentWorld, WasiVersion, WasmBinaryType};
 
 pub use crate::sandbox::stdio::Stdio;
 use crate::sys::container::instance;
@@ -1,6 +1,11 @@
 use anyhow::bail;
+use oci_spec::image::{Descriptor, MediaType, Platform, PlatformBuilder};
+use oci_spec::runtime::{ProcessBuilder, Spec, SpecBuilder};
+use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
 
+use super::context::WasiContext;
 use crate::container::{Engine, RuntimeContext, Stdio};
+use crate::sandbox::oci::{WasmLayer, WASM_ARTIFACT_LAYER_MEDIA_TYPE};
 use crate::sys::container::instance::Instance;
 use crate::testing::WasiTest;
 
@@ -36,3 +41,61 @@ fn test_validation_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[derive(Clone, Default)]
+struct EngineWithDefaultValidation;
+
+impl Engine for EngineWithDefaultValidation {
+    fn name() -> &'static str {
+        "default_validation"
+    }
+    fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+        Ok(0)
+    }
+}
+
+fn can_handle_oci(os: &str, media_type: &str, wasm: &str) -> anyhow::Result<()> {
+    let spec: Spec = SpecBuilder::default()
+        .process(
+            ProcessBuilder::default()
+                .args(vec!["_start".to_string()])
+                .build()?,
+        )
+        .build()?;
+    let layers = [WasmLayer {
+        config: Descriptor::new(MediaType::Other(media_type.to_string()), 0, "sha256:1234"),
+        layer: wat::parse_str(wasm)?.into(),
+    }];
+    let platform: Platform = PlatformBuilder::default()
+        .os(os)
+        .architecture("wasm")
+        .build()?;
+    let ctx = WasiContext {
+        spec: &spec,
+        wasm_layers: &layers,
+        platform: &platform,
+    };
+    EngineWithDefaultValidation.can_handle(&ctx)
+}
+
+#[test]
+fn test_can_handle_checks_the_wasm_platform() -> anyhow::Result<()> {
+    can_handle_oci("wasip1", WASM_ARTIFACT_LAYER_MEDIA_TYPE, "(module)")?;
+    can_handle_oci("wasi", WASM_ARTIFACT_LAYER_MEDIA_TYPE, "(module)")?;
+
+    // a component requires wasip2
+    let err = can_handle_oci("wasip1", WASM_ARTIFACT_LAYER_MEDIA_TYPE, "(component)").unwrap_err();
+    assert!(format!("{err:#}").contains("requires wasip2"), "{err:#}");
+
+    // images that were built before wasip2 existed declare wasip1 for a component, and are still run
+    can_handle_oci("wasip1", WASM_LAYER_MEDIA_TYPE, "(component)")?;
+
+    // the engine only supports wasip1
+    let err = can_handle_oci("wasip2", WASM_ARTIFACT_LAYER_MEDIA_TYPE, "(module)").unwrap_err();
+    assert!(
+        format!("{err:#}").contains("doesn't support the wasip2 platform"),
+        "{err:#}"
+    );
+
+    Ok(())
+}
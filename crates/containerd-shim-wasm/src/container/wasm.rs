@@ -1,6 +1,13 @@
-use wasmparser::Parser;
+use std::fmt;
+
+use anyhow::{bail, Result};
+use oci_spec::image::{MediaType, Platform};
+use wasmparser::{Parser, Payload};
+
+use crate::sandbox::oci::WASM_ARTIFACT_LAYER_MEDIA_TYPE;
 
 /// The type of a wasm binary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum WasmBinaryType {
     /// A wasm module.
     Module,
@@ -20,3 +27,130 @@ impl WasmBinaryType {
         }
     }
 }
+
+/// The top-level imports and exports of a component,
+/// e.g. `wasi:cli/environment@0.2.0` and `wasi:cli/run@0.2.0` for a `wasi:cli/command` component.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ComponentWorld {
+    pub imports: Vec<String>,
+    pub exports: Vec<String>,
+}
+
+impl ComponentWorld {
+    /// Returns the world of a component binary.
+    /// The imports and exports of nested components and modules are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut world = Self::default();
+        let mut depth = 0;
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload? {
+                Payload::Version { .. } => depth += 1,
+                Payload::End(_) => depth -= 1,
+                Payload::ComponentImportSection(imports) if depth == 1 => {
+                    for import in imports {
+                        world.imports.push(import?.name.0.to_string());
+                    }
+                }
+                Payload::ComponentExportSection(exports) if depth == 1 => {
+                    for export in exports {
+                        world.exports.push(export?.name.0.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(world)
+    }
+
+    /// Returns whether the component exports an interface, regardless of its version,
+    /// e.g. `exports_interface("wasi:cli/run")` for a `wasi:cli/command` component.
+    pub fn exports_interface(&self, interface: &str) -> bool {
+        self.exports
+            .iter()
+            .any(|export| export.split('@').next() == Some(interface))
+    }
+}
+
+/// The version of WASI a wasm OCI image targets, as declared by the `os` of its platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasiVersion {
+    /// The `wasip1` platform, also named `wasi` before preview 2.
+    Preview1,
+    /// The `wasip2` platform.
+    Preview2,
+}
+
+impl WasiVersion {
+    /// Returns the WASI version declared by a platform, or `None` if it doesn't declare one.
+    pub fn from_platform(platform: &Platform) -> Option<Self> {
+        match platform.os().to_string().as_str() {
+            "wasip1" | "wasi" => Some(Self::Preview1),
+            "wasip2" => Some(Self::Preview2),
+            _ => None,
+        }
+    }
+
+    /// Returns the `os` of the platform for this WASI version.
+    pub fn os(&self) -> &'static str {
+        match self {
+            Self::Preview1 => "wasip1",
+            Self::Preview2 => "wasip2",
+        }
+    }
+
+    /// Checks that a wasm binary, from a layer of type `layer_media_type`, can run on this WASI version.
+    /// Modules can run on both, as engines can adapt a preview1 module to the wasip2 platform.
+    /// Components require wasip2. Images built before wasip2 existed, e.g. by `oci-tar-builder`,
+    /// declare `wasip1` whatever they contain, so a component in a `wasip1` image is only reported with a warning,
+    /// unless it's a layer of a wasm OCI artifact, whose platform is always declared explicitly.
+    pub fn check_binary(&self, binary: WasmBinaryType, layer_media_type: &MediaType) -> Result<()> {
+        match (self, binary) {
+            (Self::Preview1, WasmBinaryType::Component)
+                if layer_media_type.to_string() == WASM_ARTIFACT_LAYER_MEDIA_TYPE =>
+            {
+                bail!("the image declares the wasip1 platform, but contains a component which requires wasip2")
+            }
+            (Self::Preview1, WasmBinaryType::Component) => {
+                log::warn!("the image declares the wasip1 platform, but contains a component which requires wasip2");
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for WasiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.os())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_world() -> Result<()> {
+        let component = wat::parse_str(
+            r#"(component
+                (import "wasi:cli/environment@0.2.0" (instance))
+                (component $nested
+                    (import "nested:import/iface" (instance))
+                )
+                (core module $m (func (export "run") (result i32) i32.const 0))
+                (core instance $i (instantiate $m))
+                (func $run (result (result)) (canon lift (core func $i "run")))
+                (instance $run_instance (export "run" (func $run)))
+                (export "wasi:cli/run@0.2.0" (instance $run_instance))
+            )"#,
+        )?;
+
+        let world = ComponentWorld::from_bytes(&component)?;
+        assert_eq!(world.imports, vec!["wasi:cli/environment@0.2.0"]);
+        assert_eq!(world.exports, vec!["wasi:cli/run@0.2.0"]);
+        assert!(world.exports_interface("wasi:cli/run"));
+        assert!(!world.exports_interface("wasi:http/incoming-handler"));
+
+        Ok(())
+    }
+}
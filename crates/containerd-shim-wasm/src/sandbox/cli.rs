@@ -1,11 +1,12 @@
+use std::fs::{create_dir_all, remove_file};
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::Arc;
 
-use containerd_shim::{parse, run, Config};
-use ttrpc::Server;
+use containerd_shim::{parse, run, Config, Flags};
+use ttrpc::Client;
 
-use crate::sandbox::manager::Shim;
+use crate::sandbox::manager::{bind_server, manager_address, socket_path, Shim};
 use crate::sandbox::shim::Local;
 use crate::sandbox::{Instance, ManagerService, ShimCli};
 use crate::services::sandbox_ttrpc::{create_manager, Manager};
@@ -36,6 +37,92 @@ macro_rules! revision {
     };
 }
 
+static DEFAULT_CONTAINERD_ADDRESS: &str = "/run/containerd/containerd.sock";
+
+// Precompiles wasm images as soon as they are pulled, rather than when the first container is created, e.g.:
+//   containerd-shim-wasmtime-v1 -address /run/containerd/containerd.sock -namespace k8s.io precompile
+// Without a namespace, the images of all namespaces are precompiled.
+#[cfg(unix)]
+fn precompile_main<I>(flags: &Flags) -> !
+where
+    I: 'static + Instance + Sync + Send,
+    I::Engine: Default,
+{
+    let _ = env_logger::try_init();
+
+    let address = match flags.address.as_str() {
+        "" => DEFAULT_CONTAINERD_ADDRESS,
+        address => address,
+    };
+    let engine = I::Engine::default();
+
+    crate::sandbox::containerd::watch_images(address, &flags.namespace, |namespace, image| {
+        log::info!("precompiling image {image} in namespace {namespace}");
+        if let Err(err) = I::precompile_image(&engine, address, namespace, image) {
+            log::warn!("failed to precompile image {image}: {err}");
+        }
+    })
+}
+
+// Lists the precompiled content of the images, and removes the stale content with `gc-precompiled`, e.g.:
+//   containerd-shim-wasmtime-v1 -address /run/containerd/containerd.sock -namespace k8s.io gc-precompiled
+// Content is stale when it was precompiled by this engine with a different `can_precompile` key,
+// e.g., by a previous version of the shim.
+fn precompiled_main<I>(flags: &Flags, remove_stale: bool) -> !
+where
+    I: 'static + Instance + Sync + Send,
+    I::Engine: Default,
+{
+    let _ = env_logger::try_init();
+
+    let address = match flags.address.as_str() {
+        "" => DEFAULT_CONTAINERD_ADDRESS,
+        address => address,
+    };
+    let namespace = match flags.namespace.as_str() {
+        "" => "default",
+        namespace => namespace,
+    };
+    let engine = I::Engine::default();
+
+    let artifacts = match I::precompiled_artifacts(&engine, address, namespace, remove_stale) {
+        Ok(artifacts) => artifacts,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("IMAGE\tLAYER\tENGINE\tKEY\tDIGEST\tSIZE\tSTATUS");
+    for artifact in artifacts {
+        let status = match (artifact.stale, remove_stale) {
+            (false, _) => "current",
+            (true, false) => "stale",
+            (true, true) => "removed",
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{status}",
+            artifact.image,
+            artifact.layer,
+            artifact.engine,
+            artifact.key,
+            artifact.digest,
+            artifact.size,
+        );
+    }
+    std::process::exit(0);
+}
+
+#[cfg(windows)]
+fn precompile_main<I>(_flags: &Flags) -> !
+where
+    I: 'static + Instance + Sync + Send,
+    I::Engine: Default,
+{
+    eprintln!("error: precompiling images is not supported on Windows");
+    std::process::exit(1);
+}
+
 pub fn shim_main<'a, I>(
     name: &str,
     version: &str,
@@ -56,10 +143,20 @@ pub fn shim_main<'a, I>(
         println!("  Runtime: {name}");
         println!("  Version: {version}");
         println!("  Revision: {}", revision.into().unwrap_or("<none>"));
+        for (name, value) in I::engine_info(&I::Engine::default()) {
+            println!("  {name}: {value}");
+        }
         println!();
 
         std::process::exit(0);
     }
+    match flags.action.as_str() {
+        "precompile" => precompile_main::<I>(&flags),
+        "list-precompiled" => precompiled_main::<I>(&flags, false),
+        "gc-precompiled" => precompiled_main::<I>(&flags, true),
+        _ => {}
+    }
+
     let shim_version = shim_version.into().unwrap_or("v1");
 
     let lower_name = name.to_lowercase();
@@ -73,16 +170,31 @@ pub fn shim_main<'a, I>(
             run::<ShimCli<I>>(&shim_id, config);
         }
         s if s == shim_client => {
-            run::<Shim>(&shim_client, config);
+            run::<Shim>(&shim_daemon, config);
         }
         s if s == shim_daemon => {
             log::info!("starting up!");
-            let s: ManagerService<Local<I>> = Default::default();
+            let address = match flags.socket.as_str() {
+                "" => manager_address(),
+                socket => socket.to_string(),
+            };
+            let socket = socket_path(&address);
+            let dir = socket.parent().expect("invalid socket path");
+            create_dir_all(dir).expect("failed to create socket directory");
+
+            if Client::connect(&address).is_ok() {
+                eprintln!("error: a manager daemon is already listening on {address}");
+                std::process::exit(1);
+            }
+            // a previous daemon didn't clean up after itself
+            let _ = remove_file(socket);
+
+            let s: ManagerService<Local<I>> =
+                ManagerService::new(Default::default()).with_state_dir(dir.join("sandboxes"));
             let s = Arc::new(Box::new(s) as Box<dyn Manager + Send + Sync>);
             let service = create_manager(s);
 
-            let mut server = Server::new()
-                .bind("unix:///run/io.containerd.wasmwasi.v1/manager.sock")
+            let mut server = bind_server(&address)
                 .expect("failed to bind to socket")
                 .register_service(service);
 
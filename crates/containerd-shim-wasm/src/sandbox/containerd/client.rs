@@ -1,23 +1,27 @@
 #![cfg(unix)]
 
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::create_dir_all;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use containerd_client;
 use containerd_client::services::v1::containers_client::ContainersClient;
 use containerd_client::services::v1::content_client::ContentClient;
+use containerd_client::services::v1::events_client::EventsClient;
 use containerd_client::services::v1::images_client::ImagesClient;
 use containerd_client::services::v1::leases_client::LeasesClient;
 use containerd_client::services::v1::{
-    Container, DeleteContentRequest, GetContainerRequest, GetImageRequest, Image, Info,
-    InfoRequest, ReadContentRequest, UpdateRequest, WriteAction, WriteContentRequest,
-    WriteContentResponse,
+    Container, DeleteContentRequest, GetContainerRequest, GetImageRequest, Image, ImageCreate,
+    ImageUpdate, Info, InfoRequest, ListImagesRequest, ReadContentRequest, SubscribeRequest,
+    UpdateRequest, WriteAction, WriteContentRequest, WriteContentResponse,
 };
 use containerd_client::tonic::transport::Channel;
 use containerd_client::tonic::Streaming;
 use containerd_client::{tonic, with_namespace};
 use futures::TryStreamExt;
-use oci_spec::image::{Arch, ImageManifest, MediaType, Platform};
+use oci_spec::image::{Arch, Descriptor, ImageIndex, ImageManifest, MediaType, Platform};
+use prost::Message;
 use prost_types::FieldMask;
 use sha256::digest;
 use tokio::runtime::Runtime;
@@ -28,10 +32,17 @@ use tonic::{Code, Request};
 use super::lease::LeaseGuard;
 use crate::container::Engine;
 use crate::sandbox::error::{Error as ShimError, Result};
-use crate::sandbox::oci::{self, WasmLayer};
+use crate::sandbox::oci::{
+    self, LayerContent, PrecompiledArtifact, WasmLayer, WASM_ARTIFACT_CONFIG_MEDIA_TYPE,
+    WASM_ARTIFACT_LAYER_MEDIA_TYPE,
+};
+use crate::sandbox::signature::{
+    signing_payload, Signature, TrustStore, SIGNATURE_LAYER_MEDIA_TYPE,
+};
 use crate::with_lease;
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
+static GC_REF_PRECOMPILE_PREFIX: &str = "containerd.io/gc.ref.content.precompile";
 // 16MB is the default maximum gRPC message size for gRPC in containerd:
 // https://github.com/containerd/containerd/blob/main/defaults/defaults.go
 // Conservatively set the max to 15MB to leave room for message overhead
@@ -42,6 +53,7 @@ pub struct Client {
     rt: Runtime,
     namespace: String,
     address: String,
+    layers_dir: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -70,9 +82,17 @@ impl Client {
             rt,
             namespace: namespace.to_string(),
             address: address.to_string(),
+            layers_dir: None,
         })
     }
 
+    // the layers read by the client are stored in `dir` instead of the system temporary directory,
+    // so that they are not left behind in an unrelated directory if the shim crashes.
+    pub fn with_layers_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.layers_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
     // wrapper around read that will read the entire content file
     fn read_content(&self, digest: impl ToString) -> Result<Vec<u8>> {
         self.rt.block_on(async {
@@ -93,8 +113,42 @@ impl Client {
         })
     }
 
-    // used in tests to clean up content
-    #[allow(dead_code)]
+    // wrapper around read that will stream the content to a memory mapped temporary file,
+    // used for layers which can be too big to be kept on the heap
+    fn read_layer_content(&self, digest: impl ToString) -> Result<LayerContent> {
+        self.rt.block_on(async {
+            let req = ReadContentRequest {
+                digest: digest.to_string(),
+                ..Default::default()
+            };
+            let req = with_namespace!(req, self.namespace);
+            let mut stream = ContentClient::new(self.inner.clone())
+                .read(req)
+                .await
+                .map_err(|err| ShimError::Containerd(err.to_string()))?
+                .into_inner();
+
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("runwasi-layer-");
+            let mut file = match &self.layers_dir {
+                Some(dir) => {
+                    create_dir_all(dir)?;
+                    builder.tempfile_in(dir)?
+                }
+                None => builder.tempfile()?,
+            };
+            while let Some(msg) = stream
+                .try_next()
+                .await
+                .map_err(|err| ShimError::Containerd(err.to_string()))?
+            {
+                file.write_all(&msg.data)?;
+            }
+            file.flush()?;
+            Ok(LayerContent::from_temp_file(file)?)
+        })
+    }
+
     fn delete_content(&self, digest: impl ToString) -> Result<()> {
         self.rt.block_on(async {
             let req = DeleteContentRequest {
@@ -334,6 +388,20 @@ impl Client {
         })
     }
 
+    fn list_images(&self) -> Result<Vec<Image>> {
+        self.rt.block_on(async {
+            let req = ListImagesRequest::default();
+            let req = with_namespace!(req, self.namespace);
+            let images = ImagesClient::new(self.inner.clone())
+                .list(req)
+                .await
+                .map_err(|err| ShimError::Containerd(err.to_string()))?
+                .into_inner()
+                .images;
+            Ok(images)
+        })
+    }
+
     fn extract_image_content_sha(&self, image: &Image) -> Result<String> {
         let digest = image
             .target
@@ -370,11 +438,41 @@ impl Client {
         })
     }
 
-    fn get_image_manifest_and_digest(&self, image_name: &str) -> Result<(ImageManifest, String)> {
+    // resolve image returns the manifest of an image. When the image is an index of several platforms,
+    // the manifest of the first wasm platform of `platforms` in the index is selected.
+    fn resolve_image(&self, image_name: &str, platforms: &[&str]) -> Result<ResolvedImage> {
         let image = self.get_image(image_name)?;
         let image_digest = self.extract_image_content_sha(&image)?;
-        let manifest = ImageManifest::from_reader(self.read_content(&image_digest)?.as_slice())?;
-        Ok((manifest, image_digest))
+        let content = self.read_content(&image_digest)?;
+
+        let media_type = image.target.map(|target| target.media_type);
+        if !media_type.as_deref().is_some_and(is_image_index) {
+            return Ok(ResolvedImage {
+                digest: image_digest,
+                manifest: Some(ImageManifest::from_reader(content.as_slice())?),
+                platform: None,
+            });
+        }
+
+        let index = ImageIndex::from_reader(content.as_slice())?;
+        let Some(descriptor) = select_manifest(&index, platforms)? else {
+            return Ok(ResolvedImage {
+                digest: image_digest,
+                manifest: None,
+                platform: None,
+            });
+        };
+        log::info!(
+            "selected manifest {} from image index {image_digest}",
+            descriptor.digest()
+        );
+        let manifest =
+            ImageManifest::from_reader(self.read_content(descriptor.digest())?.as_slice())?;
+        Ok(ResolvedImage {
+            digest: image_digest,
+            manifest: Some(manifest),
+            platform: descriptor.platform().clone(),
+        })
     }
 
     // load module will query the containerd store to find an image that has an OS of type 'wasm'
@@ -385,21 +483,211 @@ impl Client {
         containerd_id: impl ToString,
         engine: &T,
     ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
-        let container = self.get_container(containerd_id.to_string())?;
-        let (manifest, image_digest) = self.get_image_manifest_and_digest(&container.image)?;
+        let trust_store = TrustStore::from_env()?;
+        let modules = self
+            .get_container(containerd_id.to_string())
+            .and_then(|container| {
+                self.load_image_modules(&container.image, engine, trust_store.as_ref())
+            });
+        match modules {
+            // other errors fall back to the container rootfs, which would run an unverified image
+            Err(err)
+                if trust_store.is_some() && !matches!(err, ShimError::FailedPrecondition(_)) =>
+            {
+                Err(ShimError::FailedPrecondition(format!(
+                    "failed to verify the container image, refusing to run it: {err}"
+                )))
+            }
+            modules => modules,
+        }
+    }
+
+    // precompile image will precompile the WASM layers of an image and store them in the content store,
+    // the same way `load_modules` does the first time a container is created from the image.
+    pub fn precompile_image<T: Engine>(&self, image_name: &str, engine: &T) -> Result<()> {
+        if engine.can_precompile().is_none() {
+            return Ok(());
+        }
+        let trust_store = TrustStore::from_env()?;
+        self.load_image_modules(image_name, engine, trust_store.as_ref())?;
+        Ok(())
+    }
+
+    // wrapper around the events subscription that calls `f` with the namespace and the name of every image
+    // created or updated in containerd, until the subscription ends.
+    // When the client namespace is empty, images from all the namespaces are reported.
+    pub fn watch_images(&self, mut f: impl FnMut(&str, &str)) -> Result<()> {
+        self.rt.block_on(async {
+            let filters = ["/images/create", "/images/update"]
+                .iter()
+                .map(|topic| match self.namespace.as_str() {
+                    "" => format!("topic=={topic:?}"),
+                    namespace => format!("topic=={topic:?},namespace=={namespace:?}"),
+                })
+                .collect();
+            let req = SubscribeRequest { filters };
+            let mut events = EventsClient::new(self.inner.clone())
+                .subscribe(req)
+                .await
+                .map_err(|err| ShimError::Containerd(err.to_string()))?
+                .into_inner();
+
+            while let Some(envelope) = events
+                .message()
+                .await
+                .map_err(|err| ShimError::Containerd(err.to_string()))?
+            {
+                let Some(event) = envelope.event else {
+                    continue;
+                };
+                let name = match envelope.topic.as_str() {
+                    "/images/create" => ImageCreate::decode(event.value.as_slice()).map(|e| e.name),
+                    "/images/update" => ImageUpdate::decode(event.value.as_slice()).map(|e| e.name),
+                    _ => continue,
+                };
+                match name {
+                    Ok(name) => f(&envelope.namespace, &name),
+                    Err(err) => log::warn!("failed to decode {} event: {err}", envelope.topic),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // precompiled artifacts lists the precompiled content of the layers of every image in the namespace,
+    // as labeled by `load_modules`.
+    // Artifacts of `T` with a key different from the current `can_precompile` value are marked as stale.
+    pub fn precompiled_artifacts<T: Engine>(&self, engine: &T) -> Result<Vec<PrecompiledArtifact>> {
+        let current_key = engine.can_precompile();
+        let mut artifacts = vec![];
+        for image in self.list_images()? {
+            let manifest = match self.resolve_image(&image.name, T::supported_platforms()) {
+                Ok(ResolvedImage {
+                    manifest: Some(manifest),
+                    ..
+                }) => manifest,
+                Ok(_) => continue,
+                Err(err) => {
+                    log::debug!("skipping image {}: {err}", image.name);
+                    continue;
+                }
+            };
+            for layer in manifest.layers() {
+                let info = match self.get_info(layer.digest()) {
+                    Ok(info) => info,
+                    Err(err) => {
+                        log::debug!(
+                            "skipping layer {} of image {}: {err}",
+                            layer.digest(),
+                            image.name
+                        );
+                        continue;
+                    }
+                };
+                for (label, digest) in &info.labels {
+                    let Some((engine_name, key)) = parse_precompile_label(label) else {
+                        continue;
+                    };
+                    let size = match self.get_info(digest) {
+                        Ok(info) => info.size,
+                        Err(err) => {
+                            log::warn!("precompiled content {digest} is missing: {err}");
+                            0
+                        }
+                    };
+                    let stale = engine_name == T::name() && current_key.as_deref() != Some(key);
+                    artifacts.push(PrecompiledArtifact {
+                        image: image.name.clone(),
+                        layer: layer.digest().clone(),
+                        engine: engine_name.to_string(),
+                        key: key.to_string(),
+                        digest: digest.clone(),
+                        size,
+                        stale,
+                    });
+                }
+            }
+        }
+        Ok(artifacts)
+    }
+
+    // remove precompiled artifact removes the labels linking the precompiled content to its layer and image,
+    // and deletes the precompiled content.
+    pub fn remove_precompiled_artifact(&self, artifact: &PrecompiledArtifact) -> Result<()> {
+        let precompile_id = precompile_label(&artifact.engine, &artifact.key);
+        let is_artifact_label = |label: &String, value: &String| {
+            *label == precompile_id
+                || (label.starts_with(GC_REF_PRECOMPILE_PREFIX) && *value == artifact.digest)
+        };
+
+        let mut layer = self.get_info(&artifact.layer)?;
+        layer.labels.retain(|k, v| !is_artifact_label(k, v));
+        self.update_info(layer)?;
+
+        let image = self.get_image(&artifact.image)?;
+        let image_digest = self.extract_image_content_sha(&image)?;
+        let mut image = self.get_info(&image_digest)?;
+        image.labels.retain(|k, v| !is_artifact_label(k, v));
+        self.update_info(image)?;
+
+        // without the gc references containerd will collect the content eventually anyway
+        if let Err(err) = self.delete_content(&artifact.digest) {
+            log::warn!(
+                "failed to delete precompiled content {}: {err}",
+                artifact.digest
+            );
+        }
+        Ok(())
+    }
+
+    fn load_image_modules<T: Engine>(
+        &self,
+        image_name: &str,
+        engine: &T,
+        trust_store: Option<&TrustStore>,
+    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+        let image = self.resolve_image(image_name, T::supported_platforms())?;
+        let image_digest = image.digest;
+        let Some(manifest) = image.manifest else {
+            if trust_store.is_some() {
+                return Err(ShimError::FailedPrecondition(
+                    "image index doesn't contain a signed WASM manifest, refusing to run it"
+                        .to_string(),
+                ));
+            }
+            log::info!("image index doesn't contain a WASM manifest");
+            return Ok((vec![], Platform::default()));
+        };
 
         let image_config_descriptor = manifest.config();
         let image_config = self.read_content(image_config_descriptor.digest())?;
         let image_config = image_config.as_slice();
 
-        // the only part we care about here is the platform values
-        let platform: Platform = serde_json::from_slice(image_config)?;
+        // with a trust store every image is verified, whatever its platform,
+        // as images that aren't run from wasm layers are run from their rootfs instead.
+        if let Some(trust_store) = trust_store {
+            verify_digest(image_config_descriptor.digest(), image_config)?;
+            self.verify_signatures(&manifest, trust_store)?;
+        }
+
+        // the only part we care about here is the platform values,
+        // the platform the manifest was selected for in an image index takes precedence.
+        // The config of wasm OCI artifacts has the same `architecture` and `os` fields as an image config.
+        let platform = match image.platform {
+            Some(platform) => platform,
+            None => serde_json::from_slice(image_config)?,
+        };
         let Arch::Wasm = platform.architecture() else {
             log::info!("manifest is not in WASM OCI image format");
             return Ok((vec![], platform));
         };
 
-        log::info!("found manifest with WASM OCI image format");
+        if image_config_descriptor.media_type().to_string() == WASM_ARTIFACT_CONFIG_MEDIA_TYPE {
+            log::info!("found manifest with WASM OCI artifact format");
+        } else {
+            log::info!("found manifest with WASM OCI image format");
+        }
+
         // This label is unique across runtimes and version of the shim running
         // a precompiled component/module will not work across different runtimes or versions
         let (can_precompile, precompile_id) = match engine.can_precompile() {
@@ -430,7 +718,7 @@ impl Client {
         }
 
         if needs_precompile {
-            log::info!("precompiling layers for image: {}", container.image);
+            log::info!("precompiling layers for image: {}", image_name);
             let compiled_layers = match engine.precompile(&layers) {
                 Ok(compiled_layers) => {
                     if compiled_layers.len() != layers.len() {
@@ -447,14 +735,13 @@ impl Client {
             };
 
             let mut layers_for_runtime = Vec::with_capacity(compiled_layers.len());
-            for (i, compiled_layer) in compiled_layers.iter().enumerate() {
-                if compiled_layer.is_none() {
+            for (i, compiled_layer) in compiled_layers.into_iter().enumerate() {
+                let Some(compiled_layer) = compiled_layer else {
                     log::debug!("no compiled layer using original");
                     layers_for_runtime.push(layers[i].clone());
                     continue;
-                }
+                };
 
-                let compiled_layer = compiled_layer.as_ref().unwrap();
                 let original_config = &layers[i].config;
                 let labels = HashMap::from([(
                     format!("{precompile_id}/original"),
@@ -476,7 +763,7 @@ impl Client {
                     .labels
                     .insert(precompile_id.clone(), precompiled_content.digest.clone());
                 original_layer.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
+                    format!("{GC_REF_PRECOMPILE_PREFIX}.{i}"),
                     precompiled_content.digest.clone(),
                 );
                 self.update_info(original_layer)?;
@@ -490,7 +777,7 @@ impl Client {
                 );
                 let mut image_content = self.get_info(&image_digest)?;
                 image_content.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
+                    format!("{GC_REF_PRECOMPILE_PREFIX}.{i}"),
                     precompiled_content.digest,
                 );
                 image_content
@@ -500,7 +787,7 @@ impl Client {
 
                 layers_for_runtime.push(WasmLayer {
                     config: original_config.clone(),
-                    layer: compiled_layer.clone(),
+                    layer: compiled_layer.into(),
                 });
             }
             return Ok((layers_for_runtime, platform));
@@ -510,6 +797,35 @@ impl Client {
         Ok((layers, platform))
     }
 
+    // verify signatures checks the signature layers of the image against the trust store,
+    // see `crate::sandbox::signature` for the format of the signatures.
+    fn verify_signatures(&self, manifest: &ImageManifest, trust_store: &TrustStore) -> Result<()> {
+        let (signature_layers, layers): (Vec<_>, Vec<_>) = manifest
+            .layers()
+            .iter()
+            .partition(|layer| layer.media_type().to_string() == SIGNATURE_LAYER_MEDIA_TYPE);
+
+        let signatures = signature_layers
+            .iter()
+            .map(|layer| {
+                let content = self.read_content(layer.digest())?;
+                verify_digest(layer.digest(), &content)?;
+                serde_json::from_slice::<Signature>(&content).map_err(|err| {
+                    ShimError::FailedPrecondition(format!(
+                        "invalid signature layer {}: {err}",
+                        layer.digest()
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let payload = signing_payload(
+            manifest.config().digest(),
+            layers.iter().map(|layer| layer.digest().as_str()),
+        );
+        trust_store.verify(&payload, &signatures)
+    }
+
     fn read_wasm_layer(
         &self,
         original_config: &oci_spec::image::Descriptor,
@@ -531,40 +847,118 @@ impl Client {
             }
         }
         log::debug!("loading digest: {} ", &digest_to_load);
-        self.read_content(&digest_to_load)
-            .map(|module| WasmLayer {
-                config: original_config.clone(),
-                layer: module,
-            })
-            .or_else(|e| {
-                // handle content being removed from the content store out of band
-                if digest_to_load != *original_config.digest() {
-                    log::error!("failed to load precompiled layer: {}", e);
-                    log::error!("falling back to original layer and marking for recompile");
-                    *needs_precompile = can_precompile; // only mark for recompile if engine is capable
-                    self.read_content(original_config.digest())
-                        .map(|module| WasmLayer {
-                            config: original_config.clone(),
-                            layer: module,
-                        })
-                } else {
-                    Err(e)
-                }
-            })
+        let module = match self.read_layer_content(&digest_to_load) {
+            Ok(module) => module,
+            // handle content being removed from the content store out of band
+            Err(e) if digest_to_load != *original_config.digest() => {
+                log::error!("failed to load precompiled layer: {}", e);
+                log::error!("falling back to original layer and marking for recompile");
+                *needs_precompile = can_precompile; // only mark for recompile if engine is capable
+                digest_to_load = original_config.digest().clone();
+                self.read_layer_content(&digest_to_load)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        // For precompiled content this is the digest recorded in the layer label when the content was saved
+        verify_digest(&digest_to_load, &module)?;
+
+        Ok(WasmLayer {
+            config: original_config.clone(),
+            layer: module,
+        })
+    }
+}
+
+// verify digest checks that the content matches the digest it was read with, so that content
+// modified in the content store behind containerd's back is never handed to the engine.
+fn verify_digest(expected: &str, content: &[u8]) -> Result<()> {
+    let Some(expected_hash) = expected.strip_prefix("sha256:") else {
+        log::warn!("can't verify content {expected}: unsupported digest algorithm");
+        return Ok(());
+    };
+    let actual_hash = digest(content);
+    if actual_hash != expected_hash {
+        return Err(ShimError::FailedPrecondition(format!(
+            "content {expected} does not match its digest, got sha256:{actual_hash}"
+        )));
+    }
+    Ok(())
+}
+
+// The manifest of an image, resolved for an engine by `Client::resolve_image`.
+struct ResolvedImage {
+    // the digest of the image content, which is the index for multi-platform images
+    digest: String,
+    // the selected manifest, `None` if the image is an index without any WASM manifest
+    manifest: Option<ImageManifest>,
+    // the platform the manifest was selected for, when the image is an index
+    platform: Option<Platform>,
+}
+
+fn is_image_index(media_type: &str) -> bool {
+    media_type == MediaType::ImageIndex.to_string()
+        || media_type == "application/vnd.docker.distribution.manifest.list.v2+json"
+}
+
+// select manifest returns the descriptor of the manifest for the first of `platforms` in an image index,
+// `None` if the index doesn't have any WASM manifest, and an error if it only has manifests for other WASM platforms.
+fn select_manifest<'a>(
+    index: &'a ImageIndex,
+    platforms: &[&str],
+) -> Result<Option<&'a Descriptor>> {
+    let wasm_manifests: Vec<(&Descriptor, String)> = index
+        .manifests()
+        .iter()
+        .filter_map(|descriptor| {
+            let platform = descriptor.platform().as_ref()?;
+            let Arch::Wasm = platform.architecture() else {
+                return None;
+            };
+            // `wasi` is the name used for wasip1 before preview 2
+            let os = match platform.os().to_string() {
+                os if os == "wasi" => "wasip1".to_string(),
+                os => os,
+            };
+            Some((descriptor, os))
+        })
+        .collect();
+
+    if wasm_manifests.is_empty() {
+        return Ok(None);
     }
+
+    for supported in platforms {
+        if let Some((descriptor, _)) = wasm_manifests.iter().find(|(_, os)| os == supported) {
+            return Ok(Some(descriptor));
+        }
+    }
+
+    let available: Vec<_> = wasm_manifests.iter().map(|(_, os)| os.as_str()).collect();
+    Err(ShimError::FailedPrecondition(format!(
+        "image index has manifests for platforms {available:?}, but the engine only supports {platforms:?}"
+    )))
 }
 
 fn precompile_label(name: &str, version: &str) -> String {
     format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
 }
 
+// returns the engine name and key of a `runwasi.io/precompiled/<engine>/<key>` label
+fn parse_precompile_label(label: &str) -> Option<(&str, &str)> {
+    label
+        .strip_prefix(PRECOMPILE_PREFIX)?
+        .strip_prefix('/')?
+        .split_once('/')
+}
+
+// the layers of wasm OCI artifacts are supported by all engines,
+// in addition to the layer types the engine supports
 fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
-    let supported = supported_layer_types.contains(&media_type.to_string().as_str());
-    log::debug!(
-        "layer type {} is supported: {}",
-        media_type.to_string().as_str(),
-        supported
-    );
+    let media_type = media_type.to_string();
+    let supported = media_type == WASM_ARTIFACT_LAYER_MEDIA_TYPE
+        || supported_layer_types.contains(&media_type.as_str());
+    log::debug!("layer type {} is supported: {}", media_type, supported);
     supported
 }
 
@@ -595,11 +989,16 @@ mod tests {
     use std::sync::atomic::{AtomicI32, Ordering};
     use std::sync::Arc;
 
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine as _;
     use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
     use rand::prelude::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
 
     use super::*;
     use crate::container::RuntimeContext;
+    use crate::sandbox::containerd::FakeContainerd;
     use crate::sandbox::Stdio;
     use crate::testing::oci_helpers::ImageContent;
     use crate::testing::{oci_helpers, TEST_NAMESPACE};
@@ -722,7 +1121,11 @@ mod tests {
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
 
-        let (manifest, _) = client.get_image_manifest_and_digest(&image_name).unwrap();
+        let manifest = client
+            .resolve_image(&image_name, &[])
+            .unwrap()
+            .manifest
+            .unwrap();
         let original_config = manifest.layers().first().unwrap();
         let info = client.get_info(original_config.digest()).unwrap();
 
@@ -733,6 +1136,180 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_image_is_precompiled_before_container_is_created() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        client.precompile_image(&image_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+
+        // the container uses the content precompiled with the image
+        let (layers, _) = client.load_modules(container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+    }
+
+    #[test]
+    fn test_image_is_not_precompiled_when_not_supported() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, _container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let engine = FakePrecomiplerEngine::new(None);
+        client.precompile_image(&image_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let content = b"some wasm content";
+        let expected = format!("sha256:{}", digest(content.as_slice()));
+        verify_digest(&expected, content).unwrap();
+
+        let err = verify_digest(&expected, b"some other content").unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+
+        // digests with algorithms other than sha256 are not verified
+        verify_digest("sha512:1234", content).unwrap();
+    }
+
+    #[test]
+    fn test_parse_precompile_label() {
+        assert_eq!(
+            parse_precompile_label("runwasi.io/precompiled/wasmtime/1234"),
+            Some(("wasmtime", "1234"))
+        );
+        assert_eq!(
+            parse_precompile_label(&precompile_label("fake", "uuid-1")),
+            Some(("fake", "uuid-1"))
+        );
+        assert_eq!(
+            parse_precompile_label("runwasi.io/precompiled/wasmtime"),
+            None
+        );
+        assert_eq!(
+            parse_precompile_label("containerd.io/gc.ref.content.precompile.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_wasm_layer() {
+        let supported = [WASM_LAYER_MEDIA_TYPE];
+        assert!(is_wasm_layer(&WASM_LAYER_MEDIA_TYPE.into(), &supported));
+        assert!(is_wasm_layer(&"application/wasm".into(), &supported));
+        assert!(!is_wasm_layer(&MediaType::ImageLayer, &supported));
+        // even when the engine doesn't list it
+        assert!(is_wasm_layer(&"application/wasm".into(), &[]));
+    }
+
+    #[test]
+    fn test_select_manifest() {
+        let manifest = |digest: &str, os: &str, arch: Arch| {
+            let platform = oci_spec::image::PlatformBuilder::default()
+                .os(os)
+                .architecture(arch)
+                .build()
+                .unwrap();
+            oci_spec::image::DescriptorBuilder::default()
+                .media_type(MediaType::ImageManifest)
+                .digest(digest)
+                .size(0)
+                .platform(platform)
+                .build()
+                .unwrap()
+        };
+        let index = |manifests| {
+            oci_spec::image::ImageIndexBuilder::default()
+                .schema_version(2u32)
+                .manifests(manifests)
+                .build()
+                .unwrap()
+        };
+
+        let multi_platform = index(vec![
+            manifest("sha256:amd64", "linux", Arch::Amd64),
+            manifest("sha256:wasip1", "wasip1", Arch::Wasm),
+            manifest("sha256:wasip2", "wasip2", Arch::Wasm),
+        ]);
+        let selected = select_manifest(&multi_platform, &["wasip2", "wasip1"]).unwrap();
+        assert_eq!(selected.unwrap().digest(), "sha256:wasip2");
+        let selected = select_manifest(&multi_platform, &["wasip1"]).unwrap();
+        assert_eq!(selected.unwrap().digest(), "sha256:wasip1");
+
+        // `wasi` is an alias of wasip1
+        let legacy = index(vec![manifest("sha256:wasi", "wasi", Arch::Wasm)]);
+        let selected = select_manifest(&legacy, &["wasip1"]).unwrap();
+        assert_eq!(selected.unwrap().digest(), "sha256:wasi");
+
+        // not a wasm image
+        let native = index(vec![manifest("sha256:amd64", "linux", Arch::Amd64)]);
+        assert!(select_manifest(&native, &["wasip1"]).unwrap().is_none());
+
+        // only unsupported wasm platforms
+        let wasip2 = index(vec![manifest("sha256:wasip2", "wasip2", Arch::Wasm)]);
+        let err = select_manifest(&wasip2, &["wasip1"]).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+    }
+
+    #[test]
+    fn test_stale_precompiled_artifacts_are_removed() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, _container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        client.precompile_image(&image_name, &engine).unwrap();
+
+        let artifacts = client.precompiled_artifacts(&engine).unwrap();
+        let artifact = artifacts
+            .iter()
+            .find(|a| a.image == image_name)
+            .expect("precompiled artifact not listed")
+            .clone();
+        assert_eq!(artifact.engine, FakePrecomiplerEngine::name());
+        assert_eq!(artifact.key, engine.can_precompile().unwrap());
+        assert_eq!(artifact.size, fake_precompiled_bytes.bytes.len() as i64);
+        assert!(!artifact.stale);
+
+        // a new version of the engine can't use the precompiled content anymore
+        engine.precompile_id = Some("new_version".to_string());
+        let artifacts = client.precompiled_artifacts(&engine).unwrap();
+        let artifact = artifacts.iter().find(|a| a.image == image_name).unwrap();
+        assert!(artifact.stale);
+
+        client.remove_precompiled_artifact(artifact).unwrap();
+        let artifacts = client.precompiled_artifacts(&engine).unwrap();
+        assert!(!artifacts.iter().any(|a| a.image == image_name));
+
+        let image_digest = client.resolve_image(&image_name, &[]).unwrap().digest;
+        let image_info = client.get_info(&image_digest).unwrap();
+        assert!(!image_info
+            .labels
+            .keys()
+            .any(|label| label.starts_with(PRECOMPILE_PREFIX)
+                || label.starts_with(GC_REF_PRECOMPILE_PREFIX)));
+        assert!(client.get_info(&artifact.digest).is_err());
+    }
+
     #[test]
     fn test_layers_are_precompiled_but_not_for_all_layers() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -863,7 +1440,11 @@ mod tests {
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
         assert_eq!(layers[1].layer, fake_precompiled_bytes2.bytes);
 
-        let (manifest, _) = client.get_image_manifest_and_digest(&image_name).unwrap();
+        let manifest = client
+            .resolve_image(&image_name, &[])
+            .unwrap()
+            .manifest
+            .unwrap();
 
         let original_config1 = manifest.layers().first().unwrap();
         let info1 = client.get_info(original_config1.digest()).unwrap();
@@ -882,6 +1463,253 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_save_content_in_chunks() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+
+        // bigger than a single write request
+        let data = vec![42u8; MAX_WRITE_CHUNK_SIZE_BYTES as usize + 1024];
+        let expected = format!("sha256:{}", digest(data.as_slice()));
+        let labels = HashMap::from([("label".to_string(), "value".to_string())]);
+
+        let content = client.save_content(data.clone(), "test", labels).unwrap();
+        assert_eq!(content.digest, expected);
+        assert!(containerd.has_lease("precompile-test"));
+        assert_eq!(client.read_content(&expected).unwrap(), data);
+        let info = client.get_info(&expected).unwrap();
+        assert_eq!(info.size, data.len() as i64);
+        assert_eq!(info.labels["label"], "value");
+
+        // the lease is released with the content guard
+        drop(content);
+        assert!(!containerd.has_lease("precompile-test"));
+
+        // saving existing content returns early
+        let content = client.save_content(data, "test", HashMap::new()).unwrap();
+        assert_eq!(content.digest, expected);
+    }
+
+    #[test]
+    fn test_precompiled_layers_are_cached_in_the_content_store() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let non_wasm_bytes = generate_content("original_dont_compile", "textfile");
+        let (image_name, container_name) =
+            generate_fake_container(&containerd, &[&fake_bytes, &non_wasm_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        let precompile_id = precompile_label(
+            FakePrecomiplerEngine::name(),
+            engine.can_precompile().unwrap().as_str(),
+        );
+
+        let (layers, platform) = client.load_modules(&container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        assert_eq!(layers[1].layer, non_wasm_bytes.bytes);
+        assert_eq!(platform.os().to_string(), "wasip1");
+
+        // the original layer and the image reference the precompiled content
+        let precompiled_digest = format!("sha256:{}", digest(&fake_precompiled_bytes.bytes));
+        let image = client.resolve_image(&image_name, &[]).unwrap();
+        let original_layer = image.manifest.unwrap().layers()[0].clone();
+        let info = client.get_info(original_layer.digest()).unwrap();
+        assert_eq!(info.labels[&precompile_id], precompiled_digest);
+        assert_eq!(
+            info.labels[&format!("{GC_REF_PRECOMPILE_PREFIX}.0")],
+            precompiled_digest
+        );
+        let image_info = client.get_info(&image.digest).unwrap();
+        assert_eq!(image_info.labels[&precompile_id], "true");
+
+        let (layers, _) = client.load_modules(&container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+
+        // a new version of the engine precompiles the layers again
+        engine.precompile_id = Some("new_version".to_string());
+        client.load_modules(&container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_layers_are_recompiled_when_precompiled_content_is_missing() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name) = generate_fake_container(&containerd, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        client.load_modules(&container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+
+        let precompiled_digest = format!("sha256:{}", digest(&fake_precompiled_bytes.bytes));
+        containerd.remove_content(&precompiled_digest);
+
+        let (layers, _) = client.load_modules(&container_name, &engine).unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        assert!(containerd.has_content(&precompiled_digest));
+    }
+
+    #[test]
+    fn test_precompiled_artifacts_are_removed_without_containerd() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, _container_name) = generate_fake_container(&containerd, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        client.precompile_image(&image_name, &engine).unwrap();
+
+        let artifacts = client.precompiled_artifacts(&engine).unwrap();
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].image, image_name);
+        assert_eq!(artifacts[0].size, fake_precompiled_bytes.bytes.len() as i64);
+        assert!(!artifacts[0].stale);
+
+        client.remove_precompiled_artifact(&artifacts[0]).unwrap();
+        assert!(client.precompiled_artifacts(&engine).unwrap().is_empty());
+        assert!(!containerd.has_content(&artifacts[0].digest));
+    }
+
+    #[test]
+    fn test_precompiled_artifacts_skip_missing_layers() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, _container_name) = generate_fake_container(&containerd, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        client.precompile_image(&image_name, &engine).unwrap();
+
+        // an image whose layer was garbage collected doesn't prevent listing the others
+        let missing_bytes = generate_content("missing", WASM_LAYER_MEDIA_TYPE);
+        generate_fake_container(&containerd, &[&missing_bytes]);
+        containerd.remove_content(&format!("sha256:{}", digest(missing_bytes.bytes.clone())));
+
+        let artifacts = client.precompiled_artifacts(&engine).unwrap();
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].image, image_name);
+    }
+
+    fn signature_layer(key: &Ed25519KeyPair, payload: &[u8]) -> ImageContent {
+        let signature = Signature {
+            signature: BASE64.encode(key.sign(payload)),
+        };
+        ImageContent {
+            bytes: serde_json::to_vec(&signature).unwrap(),
+            media_type: SIGNATURE_LAYER_MEDIA_TYPE.to_string(),
+        }
+    }
+
+    fn ed25519_key() -> Ed25519KeyPair {
+        let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(doc.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_trust_store_refuses_unsigned_images() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+        let trust_store = TrustStore::with_ed25519_key(ed25519_key().public_key().as_ref());
+        let engine = FakePrecomiplerEngine::new(None);
+        let module = generate_content("module", WASM_LAYER_MEDIA_TYPE);
+
+        let (wasm_image, _container_name) = generate_fake_container(&containerd, &[&module]);
+        let err = client
+            .load_image_modules(&wasm_image, &engine, Some(&trust_store))
+            .unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+        assert!(err.to_string().contains("image is not signed"), "{err}");
+
+        // images that aren't run from their wasm layers aren't run from their rootfs either
+        let linux_image = format!("localhost/linux:latest{}", random_number());
+        containerd
+            .import_platform_image(&linux_image, "linux", Arch::Amd64, &[&module])
+            .unwrap();
+        let (layers, _) = client
+            .load_image_modules(&linux_image, &engine, None)
+            .unwrap();
+        assert!(layers.is_empty());
+        let err = client
+            .load_image_modules(&linux_image, &engine, Some(&trust_store))
+            .unwrap_err();
+        assert!(err.to_string().contains("image is not signed"), "{err}");
+    }
+
+    #[test]
+    fn test_trust_store_verifies_the_config_and_layers() {
+        let containerd = FakeContainerd::start().unwrap();
+        let client = Client::connect(containerd.address(), TEST_NAMESPACE).unwrap();
+        let key = ed25519_key();
+        let trust_store = TrustStore::with_ed25519_key(key.public_key().as_ref());
+        let engine = FakePrecomiplerEngine::new(None);
+        let module = generate_content("module", WASM_LAYER_MEDIA_TYPE);
+
+        // all the images of the fake have the same config, read it from an unsigned image
+        let (unsigned, _container_name) = generate_fake_container(&containerd, &[&module]);
+        let manifest = client
+            .resolve_image(&unsigned, &[])
+            .unwrap()
+            .manifest
+            .unwrap();
+        let config_digest = manifest.config().digest();
+        let layer_digest = manifest.layers()[0].digest();
+
+        let payload = signing_payload(config_digest, [layer_digest.as_str()]);
+        let signature = signature_layer(&key, &payload);
+        let (signed, _container_name) =
+            generate_fake_container(&containerd, &[&module, &signature]);
+        let (layers, _) = client
+            .load_image_modules(&signed, &engine, Some(&trust_store))
+            .unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, module.bytes);
+
+        // a signature of the same layers with another config doesn't verify this image
+        let other_config = format!("sha256:{}", digest("other config"));
+        let payload = signing_payload(&other_config, [layer_digest.as_str()]);
+        let signature = signature_layer(&key, &payload);
+        let (tampered, _container_name) =
+            generate_fake_container(&containerd, &[&module, &signature]);
+        let err = client
+            .load_image_modules(&tampered, &engine, Some(&trust_store))
+            .unwrap_err();
+        assert!(err.to_string().contains("no valid signature"), "{err}");
+    }
+
+    fn generate_fake_container(
+        containerd: &FakeContainerd,
+        original: &[&oci_helpers::ImageContent],
+    ) -> (String, String) {
+        let _ = env_logger::try_init();
+
+        let random_number = random_number();
+        let image_name = format!("localhost/test:latest{}", random_number);
+        containerd.import_image(&image_name, original).unwrap();
+
+        let container_name = format!("test-container-{}", random_number);
+        containerd.create_container(&container_name, &image_name);
+        (image_name, container_name)
+    }
+
     fn generate_test_container(
         name: Option<String>,
         original: &[&oci_helpers::ImageContent],
@@ -895,10 +1723,7 @@ mod tests {
         let container_name = format!("test-container-{}", random_number);
         oci_helpers::create_container(&container_name, &image_name).unwrap();
 
-        let _cleanup = oci_helpers::OCICleanup {
-            image_name: image_name.clone(),
-            container_name: container_name.clone(),
-        };
+        let _cleanup = oci_helpers::OCICleanup::new(image_name.clone(), container_name.clone());
         (image_name, container_name, _cleanup)
     }
 
@@ -986,7 +1811,7 @@ mod tests {
                     continue;
                 }
 
-                let key = digest(layer.layer.clone());
+                let key = digest(&layer.layer[..]);
                 if self.precompiled_layers.values().any(|l| digest(l) == key) {
                     // simulate scenario were one of the layers is already compiled
                     compiled_layers.push(None);
@@ -0,0 +1,626 @@
+//! An in-process stand-in for the containerd daemon, used to test `Client` and the OCI images of
+//! [`WasiTestBuilder::as_oci_image`](crate::testing::WasiTestBuilder::as_oci_image) without a running containerd.
+//!
+//! It serves the subset of the content, images, containers and leases gRPC services that `Client` uses,
+//! backed by an in-memory store, on a unix socket in a temporary directory.
+//! Namespaces are ignored: all the requests share the same store.
+//! Content is never garbage collected, and leases have no effect other than their own existence.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use containerd_client::services::v1::{
+    Container, CreateRequest, CreateResponse, DeleteContentRequest, DeleteRequest,
+    GetContainerRequest, GetContainerResponse, GetImageRequest, GetImageResponse, Image, Info,
+    InfoRequest, InfoResponse, Lease, ListImagesRequest, ListImagesResponse, ReadContentRequest,
+    ReadContentResponse, UpdateRequest, UpdateResponse, WriteAction, WriteContentRequest,
+    WriteContentResponse,
+};
+use containerd_client::tonic;
+use containerd_client::types::Descriptor;
+use oci_spec::image::{self as spec, Arch, MediaType};
+use prost_types::Timestamp;
+use sha256::digest;
+use tempfile::TempDir;
+use tokio::net::UnixListener;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+use tonic::server::{Grpc, NamedService};
+use tonic::transport::{Body, Server};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::testing::oci_helpers::ImageContent;
+
+// maximum size of the data sent in a single read response
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+// containerd accepts messages of up to 16MB, more than tonic's default
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct Store {
+    // content info and data by digest
+    content: HashMap<String, (Info, Vec<u8>)>,
+    images: HashMap<String, Image>,
+    containers: HashMap<String, Container>,
+    leases: HashMap<String, Lease>,
+}
+
+#[derive(Clone, Default)]
+struct SharedStore(Arc<Mutex<Store>>);
+
+impl SharedStore {
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        self.0.lock().unwrap()
+    }
+}
+
+/// A fake containerd daemon, see the [module documentation](self).
+pub struct FakeContainerd {
+    store: SharedStore,
+    address: String,
+    // the server runs until the runtime is dropped
+    _rt: Runtime,
+    _dir: TempDir,
+}
+
+impl FakeContainerd {
+    /// Starts serving on a new socket, the server is stopped when the returned value is dropped.
+    pub fn start() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("containerd.sock");
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+
+        let store = SharedStore::default();
+        let listener = rt.block_on(async { UnixListener::bind(&path) })?;
+        let server = Server::builder()
+            .add_service(ContentService(store.clone()))
+            .add_service(ImagesService(store.clone()))
+            .add_service(ContainersService(store.clone()))
+            .add_service(LeasesService(store.clone()))
+            .serve_with_incoming(UnixListenerStream::new(listener));
+        rt.spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("fake containerd server failed: {err}");
+            }
+        });
+
+        Ok(Self {
+            store,
+            address: path.to_string_lossy().into_owned(),
+            _rt: rt,
+            _dir: dir,
+        })
+    }
+
+    /// The path of the socket to connect to.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Adds content to the content store and returns its digest.
+    pub fn add_content(&self, data: impl Into<Vec<u8>>) -> String {
+        let data = data.into();
+        let digest = format!("sha256:{}", digest(data.as_slice()));
+        let now = now();
+        let info = Info {
+            digest: digest.clone(),
+            size: data.len() as i64,
+            created_at: Some(now.clone()),
+            updated_at: Some(now),
+            labels: HashMap::new(),
+        };
+        self.store
+            .lock()
+            .content
+            .entry(digest.clone())
+            .or_insert((info, data));
+        digest
+    }
+
+    /// Removes content behind the client's back, as the garbage collector or an admin would.
+    pub fn remove_content(&self, digest: &str) {
+        self.store.lock().content.remove(digest);
+    }
+
+    pub fn has_content(&self, digest: &str) -> bool {
+        self.store.lock().content.contains_key(digest)
+    }
+
+    /// The labels of all the content in the content store.
+    pub fn content_labels(&self) -> Vec<(String, String)> {
+        let store = self.store.lock();
+        store
+            .content
+            .values()
+            .flat_map(|(info, _)| info.labels.clone())
+            .collect()
+    }
+
+    pub fn has_lease(&self, id: &str) -> bool {
+        self.store.lock().leases.contains_key(id)
+    }
+
+    pub fn has_image(&self, name: &str) -> bool {
+        self.store.lock().images.contains_key(name)
+    }
+
+    /// Removes an image, its content is kept as it's never garbage collected.
+    pub fn remove_image(&self, name: &str) {
+        self.store.lock().images.remove(name);
+    }
+
+    /// Stores a wasip1 image with the given layers, the same way `oci_helpers::import_image` builds it,
+    /// and returns the digest of its manifest.
+    pub fn import_image(&self, name: &str, layers: &[&ImageContent]) -> Result<String> {
+        self.import_platform_image(name, "wasip1", Arch::Wasm, layers)
+    }
+
+    /// Stores an image for the given platform with the given layers and returns the digest of its manifest.
+    pub fn import_platform_image(
+        &self,
+        name: &str,
+        os: &str,
+        arch: Arch,
+        layers: &[&ImageContent],
+    ) -> Result<String> {
+        let config = spec::ConfigBuilder::default()
+            .entrypoint(vec!["_start".to_string()])
+            .build()?;
+        let config = spec::ImageConfigurationBuilder::default()
+            .config(config)
+            .os(os)
+            .architecture(arch)
+            .rootfs(spec::RootFsBuilder::default().diff_ids(vec![]).build()?)
+            .build()?;
+        let config = serde_json::to_vec(&config)?;
+        let config = self.descriptor(MediaType::ImageConfig, config)?;
+
+        let layers = layers
+            .iter()
+            .map(|layer| self.descriptor(layer.media_type.as_str().into(), layer.bytes.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let manifest = spec::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageManifest)
+            .config(config)
+            .layers(layers)
+            .build()?;
+        let manifest = serde_json::to_vec(&manifest)?;
+        let size = manifest.len() as i64;
+        let manifest_digest = self.add_content(manifest);
+
+        let now = now();
+        let image = Image {
+            name: name.to_string(),
+            target: Some(Descriptor {
+                media_type: MediaType::ImageManifest.to_string(),
+                digest: manifest_digest.clone(),
+                size,
+                annotations: HashMap::new(),
+            }),
+            created_at: Some(now.clone()),
+            updated_at: Some(now),
+            ..Default::default()
+        };
+        self.store.lock().images.insert(name.to_string(), image);
+        Ok(manifest_digest)
+    }
+
+    pub fn create_container(&self, id: &str, image: &str) {
+        let container = Container {
+            id: id.to_string(),
+            image: image.to_string(),
+            ..Default::default()
+        };
+        self.store
+            .lock()
+            .containers
+            .insert(id.to_string(), container);
+    }
+
+    pub fn remove_container(&self, id: &str) {
+        self.store.lock().containers.remove(id);
+    }
+
+    fn descriptor(&self, media_type: MediaType, data: Vec<u8>) -> Result<spec::Descriptor> {
+        let size = data.len() as i64;
+        let digest = self.add_content(data);
+        Ok(spec::DescriptorBuilder::default()
+            .media_type(media_type)
+            .digest(digest)
+            .size(size)
+            .build()?)
+    }
+}
+
+fn now() -> Timestamp {
+    SystemTime::now().into()
+}
+
+fn not_found(kind: &str, id: &str) -> Status {
+    Status::not_found(format!("{kind} {id:?}: not found"))
+}
+
+// Each of the services only differ by the name they are routed with, the requests are dispatched by their full path.
+macro_rules! fake_service {
+    ($service:ident, $name:literal) => {
+        #[derive(Clone)]
+        struct $service(SharedStore);
+
+        impl NamedService for $service {
+            const NAME: &'static str = $name;
+        }
+
+        impl Service<http::Request<Body>> for $service {
+            type Response = http::Response<BoxBody>;
+            type Error = Infallible;
+            type Future = BoxFuture<Self::Response, Self::Error>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+                Box::pin(dispatch(self.0.clone(), req))
+            }
+        }
+    };
+}
+
+fake_service!(ContentService, "containerd.services.content.v1.Content");
+fake_service!(ImagesService, "containerd.services.images.v1.Images");
+fake_service!(
+    ContainersService,
+    "containerd.services.containers.v1.Containers"
+);
+fake_service!(LeasesService, "containerd.services.leases.v1.Leases");
+
+async fn dispatch(
+    store: SharedStore,
+    req: http::Request<Body>,
+) -> Result<http::Response<BoxBody>, Infallible> {
+    let path = req.uri().path().to_string();
+    let response = match path.as_str() {
+        "/containerd.services.content.v1.Content/Info" => {
+            unary(req, move |req| content_info(&store, req)).await
+        }
+        "/containerd.services.content.v1.Content/Update" => {
+            unary(req, move |req| content_update(&store, req)).await
+        }
+        "/containerd.services.content.v1.Content/Delete" => {
+            unary(req, move |req| content_delete(&store, req)).await
+        }
+        "/containerd.services.content.v1.Content/Read" => {
+            let handler = Handler(move |req| std::future::ready(content_read(&store, req)));
+            grpc().server_streaming(handler, req).await
+        }
+        "/containerd.services.content.v1.Content/Write" => {
+            let handler = Handler(move |req| content_write(store.clone(), req));
+            grpc().streaming(handler, req).await
+        }
+        "/containerd.services.images.v1.Images/Get" => {
+            unary(req, move |req| image_get(&store, req)).await
+        }
+        "/containerd.services.images.v1.Images/List" => {
+            unary(req, move |req| image_list(&store, req)).await
+        }
+        "/containerd.services.containers.v1.Containers/Get" => {
+            unary(req, move |req| container_get(&store, req)).await
+        }
+        "/containerd.services.leases.v1.Leases/Create" => {
+            unary(req, move |req| lease_create(&store, req)).await
+        }
+        "/containerd.services.leases.v1.Leases/Delete" => {
+            unary(req, move |req| lease_delete(&store, req)).await
+        }
+        path => Status::unimplemented(format!("{path} is not implemented by the fake containerd"))
+            .to_http(),
+    };
+    Ok(response)
+}
+
+async fn unary<Req, Resp>(
+    req: http::Request<Body>,
+    mut f: impl FnMut(Req) -> Result<Resp, Status> + Send,
+) -> http::Response<BoxBody>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+{
+    let handler = Handler(move |req: Request<Req>| {
+        std::future::ready(f(req.into_inner()).map(Response::new))
+    });
+    grpc().unary(handler, req).await
+}
+
+fn grpc<Resp, Req>() -> Grpc<ProstCodec<Resp, Req>>
+where
+    Resp: prost::Message + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+{
+    Grpc::new(ProstCodec::default()).max_decoding_message_size(MAX_MESSAGE_SIZE)
+}
+
+// Adapts a function to the `Service` trait, which tonic implements all of its server method kinds for.
+struct Handler<F>(F);
+
+impl<F, Req, Resp, Fut> Service<Request<Req>> for Handler<F>
+where
+    F: FnMut(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Resp>, Status>>,
+{
+    type Response = Response<Resp>;
+    type Error = Status;
+    type Future = Fut;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Req>) -> Self::Future {
+        (self.0)(req)
+    }
+}
+
+fn content_info(store: &SharedStore, req: InfoRequest) -> Result<InfoResponse, Status> {
+    let store = store.lock();
+    let (info, _) = store
+        .content
+        .get(&req.digest)
+        .ok_or_else(|| not_found("content", &req.digest))?;
+    Ok(InfoResponse {
+        info: Some(info.clone()),
+    })
+}
+
+fn content_update(store: &SharedStore, req: UpdateRequest) -> Result<UpdateResponse, Status> {
+    let update = req
+        .info
+        .ok_or_else(|| Status::invalid_argument("missing content info"))?;
+    let mut store = store.lock();
+    let (info, _) = store
+        .content
+        .get_mut(&update.digest)
+        .ok_or_else(|| not_found("content", &update.digest))?;
+
+    // like containerd, only the labels can be updated, either all at once or one by one
+    let paths = match req.update_mask {
+        Some(mask) if !mask.paths.is_empty() => mask.paths,
+        _ => vec!["labels".to_string()],
+    };
+    for path in paths {
+        match path.strip_prefix("labels.") {
+            _ if path == "labels" => info.labels = update.labels.clone(),
+            Some(key) => match update.labels.get(key) {
+                Some(value) => {
+                    info.labels.insert(key.to_string(), value.clone());
+                }
+                None => {
+                    info.labels.remove(key);
+                }
+            },
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "cannot update {path:?} field on content info"
+                )))
+            }
+        }
+    }
+    info.updated_at = Some(now());
+    Ok(UpdateResponse {
+        info: Some(info.clone()),
+    })
+}
+
+fn content_delete(store: &SharedStore, req: DeleteContentRequest) -> Result<(), Status> {
+    store
+        .lock()
+        .content
+        .remove(&req.digest)
+        .map(|_| ())
+        .ok_or_else(|| not_found("content", &req.digest))
+}
+
+type ReadStream = tokio_stream::Iter<std::vec::IntoIter<Result<ReadContentResponse, Status>>>;
+
+fn content_read(
+    store: &SharedStore,
+    req: Request<ReadContentRequest>,
+) -> Result<Response<ReadStream>, Status> {
+    let req = req.into_inner();
+    let store = store.lock();
+    let (_, data) = store
+        .content
+        .get(&req.digest)
+        .ok_or_else(|| not_found("content", &req.digest))?;
+
+    let start = (req.offset.max(0) as usize).min(data.len());
+    let end = match req.size {
+        size if size > 0 => (start + size as usize).min(data.len()),
+        _ => data.len(),
+    };
+    let responses: Vec<_> = data[start..end]
+        .chunks(READ_CHUNK_SIZE)
+        .scan(start, |offset, chunk| {
+            let response = ReadContentResponse {
+                offset: *offset as i64,
+                data: chunk.to_vec(),
+            };
+            *offset += chunk.len();
+            Some(Ok(response))
+        })
+        .collect();
+    Ok(Response::new(tokio_stream::iter(responses)))
+}
+
+// Write follows containerd's protocol: the first request tells whether the content already exists,
+// then data is written at increasing offsets until the commit verifies the size and digest.
+async fn content_write(
+    store: SharedStore,
+    req: Request<Streaming<WriteContentRequest>>,
+) -> Result<Response<ReceiverStream<Result<WriteContentResponse, Status>>>, Status> {
+    let mut requests = req.into_inner();
+    let first = requests
+        .message()
+        .await?
+        .ok_or_else(|| Status::invalid_argument("empty write stream"))?;
+    if store.lock().content.contains_key(&first.expected) {
+        return Err(Status::already_exists(format!(
+            "content {}: already exists",
+            first.expected
+        )));
+    }
+
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut ingest = vec![];
+        let mut next = Some(first);
+        while let Some(req) = next.take() {
+            let is_commit = req.action == WriteAction::Commit as i32;
+            let response = content_write_one(&store, &mut ingest, req);
+            let failed = response.is_err();
+            if tx.send(response).await.is_err() || is_commit || failed {
+                return;
+            }
+            next = match requests.message().await {
+                Ok(req) => req,
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
+                    return;
+                }
+            };
+        }
+    });
+    Ok(Response::new(ReceiverStream::new(rx)))
+}
+
+fn content_write_one(
+    store: &SharedStore,
+    ingest: &mut Vec<u8>,
+    req: WriteContentRequest,
+) -> Result<WriteContentResponse, Status> {
+    let action = WriteAction::try_from(req.action)
+        .map_err(|_| Status::invalid_argument(format!("unknown write action {}", req.action)))?;
+    if action != WriteAction::Stat && !req.data.is_empty() {
+        if req.offset != ingest.len() as i64 {
+            return Err(Status::invalid_argument(format!(
+                "write at offset {} but {} bytes were written",
+                req.offset,
+                ingest.len()
+            )));
+        }
+        ingest.extend_from_slice(&req.data);
+    }
+
+    let mut response = WriteContentResponse {
+        action: req.action,
+        offset: ingest.len() as i64,
+        total: req.total,
+        ..Default::default()
+    };
+    if action != WriteAction::Commit {
+        return Ok(response);
+    }
+
+    if req.total > 0 && req.total != ingest.len() as i64 {
+        return Err(Status::failed_precondition(format!(
+            "unexpected commit size {}, expected {}",
+            ingest.len(),
+            req.total
+        )));
+    }
+    let digest = format!("sha256:{}", digest(ingest.as_slice()));
+    if !req.expected.is_empty() && req.expected != digest {
+        return Err(Status::failed_precondition(format!(
+            "unexpected commit digest {digest}, expected {}",
+            req.expected
+        )));
+    }
+    let now = now();
+    let info = Info {
+        digest: digest.clone(),
+        size: ingest.len() as i64,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+        labels: req.labels,
+    };
+    store
+        .lock()
+        .content
+        .insert(digest.clone(), (info, std::mem::take(ingest)));
+
+    response.total = response.offset;
+    response.digest = digest;
+    Ok(response)
+}
+
+fn image_get(store: &SharedStore, req: GetImageRequest) -> Result<GetImageResponse, Status> {
+    let image = store
+        .lock()
+        .images
+        .get(&req.name)
+        .cloned()
+        .ok_or_else(|| not_found("image", &req.name))?;
+    Ok(GetImageResponse { image: Some(image) })
+}
+
+fn image_list(store: &SharedStore, req: ListImagesRequest) -> Result<ListImagesResponse, Status> {
+    if !req.filters.is_empty() {
+        return Err(Status::unimplemented("image filters are not supported"));
+    }
+    let images = store.lock().images.values().cloned().collect();
+    Ok(ListImagesResponse { images })
+}
+
+fn container_get(
+    store: &SharedStore,
+    req: GetContainerRequest,
+) -> Result<GetContainerResponse, Status> {
+    let container = store
+        .lock()
+        .containers
+        .get(&req.id)
+        .cloned()
+        .ok_or_else(|| not_found("container", &req.id))?;
+    Ok(GetContainerResponse {
+        container: Some(container),
+    })
+}
+
+fn lease_create(store: &SharedStore, req: CreateRequest) -> Result<CreateResponse, Status> {
+    let mut store = store.lock();
+    if store.leases.contains_key(&req.id) {
+        return Err(Status::already_exists(format!(
+            "lease {:?}: already exists",
+            req.id
+        )));
+    }
+    let lease = Lease {
+        id: req.id.clone(),
+        created_at: Some(now()),
+        labels: req.labels,
+    };
+    store.leases.insert(req.id, lease.clone());
+    Ok(CreateResponse { lease: Some(lease) })
+}
+
+fn lease_delete(store: &SharedStore, req: DeleteRequest) -> Result<(), Status> {
+    store
+        .lock()
+        .leases
+        .remove(&req.id)
+        .map(|_| ())
+        .ok_or_else(|| not_found("lease", &req.id))
+}
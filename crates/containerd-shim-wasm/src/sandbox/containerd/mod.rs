@@ -1,6 +1,12 @@
 #![cfg(unix)]
 
 mod client;
+#[cfg(any(test, feature = "testing"))]
+mod fake;
 mod lease;
+mod watcher;
 
 pub(crate) use client::Client;
+#[cfg(any(test, feature = "testing"))]
+pub use fake::FakeContainerd;
+pub(crate) use watcher::watch_images;
@@ -0,0 +1,39 @@
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use super::Client;
+
+static RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Calls `f` with the namespace and the name of every image created or updated in containerd.
+/// This never returns: the subscription is established again whenever it ends, e.g., when containerd restarts.
+///
+/// The subscription runs in its own thread, so that `f` can use a `Client` of its own.
+/// Images are handled one at a time, in the order they are reported.
+pub(crate) fn watch_images(address: &str, namespace: &str, mut f: impl FnMut(&str, &str)) -> ! {
+    let (tx, rx) = channel::<(String, String)>();
+
+    let address = address.to_string();
+    let namespace = namespace.to_string();
+    thread::Builder::new()
+        .name("image-watcher".to_string())
+        .spawn(move || loop {
+            let result = Client::connect(&address, &namespace).and_then(|client| {
+                log::info!("watching images in containerd at {address}");
+                client.watch_images(|namespace, image| {
+                    let _ = tx.send((namespace.to_string(), image.to_string()));
+                })
+            });
+            if let Err(err) = result {
+                log::warn!("watching images failed: {err}");
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        })
+        .expect("failed to spawn image watcher thread");
+
+    for (namespace, image) in rx {
+        f(&namespace, &image);
+    }
+    unreachable!("image watcher thread exited");
+}
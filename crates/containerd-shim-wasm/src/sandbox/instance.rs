@@ -4,8 +4,13 @@ use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
+#[cfg(any(test, feature = "testing"))]
+use oci_spec::image::Platform;
 
 use super::error::Error;
+use super::oci::PrecompiledArtifact;
+#[cfg(any(test, feature = "testing"))]
+use super::oci::WasmLayer;
 use super::sync::WaitableCell;
 use crate::sys::signals::*;
 
@@ -28,6 +33,12 @@ pub struct InstanceConfig<Engine: Send + Sync + Clone> {
     namespace: String,
     // /// GRPC address back to main containerd
     containerd_address: String,
+    /// Optional directory where the wasm layers read from containerd are stored.
+    layers_dir: Option<PathBuf>,
+    /// Optional wasm layers and platform of the container image,
+    /// used by the tests instead of loading them from containerd.
+    #[cfg(any(test, feature = "testing"))]
+    wasm_layers: Option<(Vec<WasmLayer>, Platform)>,
 }
 
 impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
@@ -46,6 +57,9 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
             stdout: PathBuf::default(),
             stderr: PathBuf::default(),
             bundle: PathBuf::default(),
+            layers_dir: None,
+            #[cfg(any(test, feature = "testing"))]
+            wasm_layers: None,
         }
     }
 
@@ -93,6 +107,18 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
         &self.bundle
     }
 
+    /// set the directory where the wasm layers of the instance are stored while it runs.
+    /// By default they are stored in the system temporary directory.
+    pub fn set_layers_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.layers_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// get the directory where the wasm layers of the instance are stored, if it was set
+    pub fn get_layers_dir(&self) -> Option<&Path> {
+        self.layers_dir.as_deref()
+    }
+
     /// get the wasm engine for the instance
     pub fn get_engine(&self) -> Engine {
         self.engine.clone()
@@ -107,6 +133,23 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
     pub fn get_containerd_address(&self) -> String {
         self.containerd_address.clone()
     }
+
+    /// set the wasm layers and platform of the container image for the instance,
+    /// instead of loading them from the containerd content store.
+    /// The layers are used as they are, they are not precompiled, nor checked against their digest or signature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_wasm_layers(&mut self, layers: Vec<WasmLayer>, platform: Platform) -> &mut Self {
+        self.wasm_layers = Some((layers, platform));
+        self
+    }
+
+    /// get the wasm layers and platform of the container image for the instance, if they were set
+    #[cfg(any(test, feature = "testing"))]
+    pub fn get_wasm_layers(&self) -> Option<(&[WasmLayer], &Platform)> {
+        self.wasm_layers
+            .as_ref()
+            .map(|(layers, platform)| (layers.as_slice(), platform))
+    }
 }
 
 /// Represents a WASI module(s).
@@ -122,6 +165,59 @@ pub trait Instance: 'static {
     where
         Self: Sized;
 
+    /// Reattach to an instance that was created by a previous shim process.
+    /// This is called when the shim restarts and rebuilds its tasks from the persisted state.
+    /// The default implementation returns an error, meaning the instance does not survive a shim restart.
+    fn reattach(id: String, _cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Err(Error::FailedPrecondition(format!(
+            "instance {id} can't be reattached after a shim restart"
+        )))
+    }
+
+    /// Precompile the wasm layers of an image in the containerd content store,
+    /// the same way they would be precompiled when the first container is created from it.
+    /// This is used by the `precompile` action of the shim binary, which precompiles images as they are pulled.
+    /// The default implementation does nothing.
+    fn precompile_image(
+        _engine: &Self::Engine,
+        _containerd_address: &str,
+        _namespace: &str,
+        _image: &str,
+    ) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
+
+    /// List the precompiled artifacts of the images in the containerd content store.
+    /// When `remove_stale` is set, the artifacts this engine can no longer use are removed.
+    /// This is used by the `list-precompiled` and `gc-precompiled` actions of the shim binary.
+    /// The default implementation returns an empty list.
+    fn precompiled_artifacts(
+        _engine: &Self::Engine,
+        _containerd_address: &str,
+        _namespace: &str,
+        _remove_stale: bool,
+    ) -> Result<Vec<PrecompiledArtifact>, Error>
+    where
+        Self: Sized,
+    {
+        Ok(vec![])
+    }
+
+    /// Information about the engine printed with the `--version` of the shim binary, as `(name, value)` pairs.
+    /// The default implementation returns nothing.
+    fn engine_info(_engine: &Self::Engine) -> Vec<(String, String)>
+    where
+        Self: Sized,
+    {
+        vec![]
+    }
+
     /// Start the instance
     /// The returned value should be a unique ID (such as a PID) for the instance.
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
@@ -160,6 +256,9 @@ impl Instance for Nop {
             exit_code: WaitableCell::new(),
         })
     }
+    fn reattach(id: String, cfg: Option<&InstanceConfig<Self::Engine>>) -> Result<Self, Error> {
+        Self::new(id, cfg)
+    }
     fn start(&self) -> Result<u32, Error> {
         Ok(std::process::id())
     }
@@ -1,12 +1,24 @@
-//! This experimental module implements a manager service which can be used to
+//! This module implements a manager service which can be used to
 //! manage multiple instances of a sandbox in-process.
 //! The idea behind this module is to only need a single shim process for the entire node rather than one per pod/container.
+//!
+//! The manager runs as a daemon listening on [`manager_address`], and the `Shim` defined here
+//! forwards the containerd shim API to it, starting the daemon if it's not running yet.
 
 use std::collections::HashMap;
-use std::env::current_dir;
-use std::path::Path;
+use std::env::{current_dir, current_exe};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+#[cfg(unix)]
+use std::os::fd::IntoRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 
 use anyhow::Context;
 use containerd_shim::error::Error as ShimError;
@@ -16,6 +28,7 @@ use containerd_shim::protos::TaskClient;
 use containerd_shim::publisher::RemotePublisher;
 use containerd_shim::{self as shim, api, TtrpcContext, TtrpcResult};
 use oci_spec::runtime::{self, Spec};
+use serde::{Deserialize, Serialize};
 use shim::Flags;
 use ttrpc::context;
 
@@ -25,6 +38,53 @@ use super::sandbox;
 use crate::services::sandbox_ttrpc::{Manager, ManagerClient};
 use crate::sys::networking::setup_namespaces;
 
+#[cfg(test)]
+mod tests;
+
+/// Default address of the manager daemon socket.
+pub const DEFAULT_MANAGER_ADDRESS: &str = "unix:///run/io.containerd.wasmwasi.v1/manager.sock";
+
+/// Environment variable overriding the address of the manager daemon socket.
+/// It's read by both the daemon and the shim connecting to it.
+pub const MANAGER_ADDRESS_ENV: &str = "RUNWASI_MANAGER_ADDRESS";
+
+// How long the shim waits for a daemon it started to accept connections.
+const DAEMON_START_RETRIES: usize = 50;
+const DAEMON_START_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the address of the manager daemon socket.
+/// This is the value of `RUNWASI_MANAGER_ADDRESS` if set, or `DEFAULT_MANAGER_ADDRESS` otherwise.
+pub fn manager_address() -> String {
+    std::env::var(MANAGER_ADDRESS_ENV).unwrap_or_else(|_| DEFAULT_MANAGER_ADDRESS.to_string())
+}
+
+/// Returns the path of the socket file for a ttrpc address.
+pub(crate) fn socket_path(address: &str) -> &Path {
+    Path::new(address.strip_prefix("unix://").unwrap_or(address))
+}
+
+/// Binds a ttrpc server to a unix socket address.
+///
+/// `Server::bind` sets `SO_REUSEPORT` on the socket, which recent kernels reject for unix sockets,
+/// so we bind the listener ourselves and hand it over to the server.
+#[cfg(unix)]
+pub(crate) fn bind_server(address: &str) -> Result<Server, Error> {
+    let listener = UnixListener::bind(socket_path(address))?;
+    listener.set_nonblocking(true)?;
+    let server = Server::new()
+        .add_listener(listener.into_raw_fd())
+        .context("could not add listener")?;
+    Ok(server)
+}
+
+#[cfg(windows)]
+pub(crate) fn bind_server(address: &str) -> Result<Server, Error> {
+    let server = Server::new()
+        .bind(address)
+        .context("could not bind to address")?;
+    Ok(server)
+}
+
 /// Sandbox wraps an Instance and is used with the `Service` to manage multiple instances.
 pub trait Sandbox: Task + Send + Sync {
     type Instance: Instance;
@@ -38,10 +98,42 @@ pub trait Sandbox: Task + Send + Sync {
     ) -> Self;
 }
 
+// The sandbox parameters as persisted by the manager, so that it can
+// reconnect the sandboxes after a daemon restart.
+#[derive(Clone, Serialize, Deserialize)]
+struct SandboxRecord {
+    id: String,
+    namespace: String,
+    ttrpc_address: String,
+    working_directory: String,
+    containerd_address: String,
+    socket_path: String,
+}
+
+impl From<sandbox::CreateRequest> for SandboxRecord {
+    fn from(req: sandbox::CreateRequest) -> Self {
+        let socket_path = format!("unix://{}/shim.sock", &req.working_directory);
+        Self {
+            id: req.id,
+            namespace: req.namespace,
+            ttrpc_address: req.ttrpc_address,
+            working_directory: req.working_directory,
+            containerd_address: req.containerd_address,
+            socket_path,
+        }
+    }
+}
+
+struct SandboxHandle {
+    socket_path: String,
+    server: Server,
+}
+
 /// Service is a manager service which can be used to manage multiple instances of a sandbox in-process.
 pub struct Service<T: Sandbox> {
-    sandboxes: RwLock<HashMap<String, String>>,
+    sandboxes: RwLock<HashMap<String, SandboxHandle>>,
     engine: <T::Instance as Instance>::Engine,
+    state_dir: Option<PathBuf>,
     phantom: std::marker::PhantomData<T>,
 }
 
@@ -50,74 +142,175 @@ impl<T: Sandbox> Service<T> {
         Self {
             sandboxes: RwLock::new(HashMap::new()),
             engine,
+            state_dir: None,
             phantom: std::marker::PhantomData,
         }
     }
 }
 
-impl<T: Sandbox> Default for Service<T>
-where
-    <T::Instance as Instance>::Engine: Default,
-{
-    fn default() -> Self {
-        Self::new(Default::default())
+impl<T: Sandbox + 'static> Service<T> {
+    /// Persists the sandboxes in `dir`, and reconnects any sandbox saved there by a previous daemon.
+    pub fn with_state_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.state_dir = Some(dir.as_ref().to_path_buf());
+        self.restore();
+        self
     }
-}
 
-impl<T: Sandbox + 'static> Manager for Service<T> {
-    fn create(
-        &self,
-        _ctx: &TtrpcContext,
-        req: sandbox::CreateRequest,
-    ) -> TtrpcResult<sandbox::CreateResponse> {
-        let mut sandboxes = self.sandboxes.write().unwrap();
+    fn restore(&self) {
+        let records = match self.load_records() {
+            Ok(records) => records,
+            Err(err) => {
+                log::warn!("could not load persisted sandboxes: {err}");
+                return;
+            }
+        };
 
-        if sandboxes.contains_key(&req.id) {
-            return Err(Error::AlreadyExists(req.id).into());
+        let mut sandboxes = self.sandboxes.write().unwrap();
+        for record in records {
+            // the socket of the previous daemon is still around, but nobody is serving it
+            let _ = remove_file(socket_path(&record.socket_path));
+
+            match self.start(&record) {
+                Ok(handle) => {
+                    log::info!("reconnected sandbox {}", record.id);
+                    sandboxes.insert(record.id.clone(), handle);
+                }
+                Err(err) => {
+                    log::warn!("could not reconnect sandbox {}: {err}", record.id);
+                    self.remove_record(&record.id);
+                }
+            }
         }
+    }
 
-        let sock = format!("unix://{}/shim.sock", &req.working_directory);
-
-        let publisher = RemotePublisher::new(req.ttrpc_address)?;
+    fn start(&self, record: &SandboxRecord) -> TtrpcResult<SandboxHandle> {
+        let publisher = RemotePublisher::new(&record.ttrpc_address)?;
 
         let sb = T::new(
-            req.namespace.clone(),
-            req.containerd_address.clone(),
-            req.id.clone(),
+            record.namespace.clone(),
+            record.containerd_address.clone(),
+            record.id.clone(),
             self.engine.clone(),
             publisher,
         );
         let task_service = create_task(Arc::new(Box::new(sb)));
-        let mut server = Server::new().bind(&sock)?.register_service(task_service);
+        let mut server = bind_server(&record.socket_path)?.register_service(task_service);
 
-        sandboxes.insert(req.id.clone(), sock.clone());
-
-        let cfg = Spec::load(
-            Path::new(&req.working_directory)
-                .join("config.json")
-                .to_str()
-                .unwrap(),
-        )
-        .map_err(|err| Error::InvalidArgument(format!("could not load runtime spec: {}", err)))?;
+        let cfg = Spec::load(Path::new(&record.working_directory).join("config.json")).map_err(
+            |err| Error::InvalidArgument(format!("could not load runtime spec: {}", err)),
+        )?;
 
-        let (tx, rx) = std::sync::mpsc::channel::<Result<(), Error>>();
+        let (tx, rx) = std::sync::mpsc::channel::<Result<Server, Error>>();
 
-        let id = &req.id;
+        let id = &record.id;
 
         let _ = thread::Builder::new()
             .name(format!("{}-sandbox-create", id))
             .spawn(move || {
-                let r = start_sandbox(cfg, &mut server);
+                let r = start_sandbox(cfg, &mut server).map(|_| server);
                 tx.send(r).context("could not send sandbox result").unwrap();
             })
             .context("failed to spawn sandbox thread")
             .map_err(Error::from)?;
 
-        rx.recv()
+        let server = rx
+            .recv()
             .context("could not receive sandbox result")
             .map_err(Error::from)??;
+
+        Ok(SandboxHandle {
+            socket_path: record.socket_path.clone(),
+            server,
+        })
+    }
+
+    fn save_record(&self, record: &SandboxRecord) -> Result<(), Error> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(());
+        };
+        create_dir_all(dir)?;
+
+        // write to a temporary file first so that a crash never leaves a truncated record behind
+        let path = dir.join(format!("{}.json", record.id));
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, record)?;
+        rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn remove_record(&self, id: &str) {
+        let Some(dir) = &self.state_dir else {
+            return;
+        };
+        if let Err(err) = remove_file(dir.join(format!("{id}.json"))) {
+            log::warn!("could not remove persisted sandbox {id}: {err}");
+        }
+    }
+
+    fn load_records(&self) -> Result<Vec<SandboxRecord>, Error> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(vec![]);
+        };
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut records = vec![];
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                records.push(serde_json::from_reader(File::open(path)?)?);
+            }
+        }
+        Ok(records)
+    }
+}
+
+impl<T: Sandbox> Default for Service<T>
+where
+    <T::Instance as Instance>::Engine: Default,
+{
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T: Sandbox + 'static> Manager for Service<T> {
+    fn create(
+        &self,
+        _ctx: &TtrpcContext,
+        req: sandbox::CreateRequest,
+    ) -> TtrpcResult<sandbox::CreateResponse> {
+        let mut sandboxes = self.sandboxes.write().unwrap();
+
+        if sandboxes.contains_key(&req.id) {
+            return Err(Error::AlreadyExists(req.id).into());
+        }
+
+        let record = SandboxRecord::from(req);
+        let handle = self.start(&record)?;
+        self.save_record(&record)?;
+
+        let socket_path = handle.socket_path.clone();
+        sandboxes.insert(record.id, handle);
+
         Ok(sandbox::CreateResponse {
-            socket_path: sock,
+            socket_path,
+            ..Default::default()
+        })
+    }
+
+    fn connect(
+        &self,
+        _ctx: &TtrpcContext,
+        req: sandbox::ConnectRequest,
+    ) -> TtrpcResult<sandbox::ConnectResponse> {
+        let sandboxes = self.sandboxes.read().unwrap();
+        let sandbox = sandboxes
+            .get(&req.id)
+            .ok_or_else(|| Error::NotFound(req.id.clone()))?;
+
+        Ok(sandbox::ConnectResponse {
+            socket_path: sandbox.socket_path.clone(),
             ..Default::default()
         })
     }
@@ -127,22 +320,33 @@ impl<T: Sandbox + 'static> Manager for Service<T> {
         _ctx: &TtrpcContext,
         req: sandbox::DeleteRequest,
     ) -> TtrpcResult<sandbox::DeleteResponse> {
-        let mut sandboxes = self.sandboxes.write().unwrap();
-        if !sandboxes.contains_key(&req.id) {
-            return Err(Error::NotFound(req.id).into());
+        // the sandboxes are not locked while the sandbox shuts down, so that it doesn't block the other requests
+        let address = match self.sandboxes.read().unwrap().get(&req.id) {
+            Some(sandbox) => sandbox.socket_path.clone(),
+            None => return Err(Error::NotFound(req.id).into()),
+        };
+
+        {
+            let c = Client::connect(&address)?;
+            let tc = TaskClient::new(c);
+
+            tc.shutdown(
+                context::Context::default(),
+                &api::ShutdownRequest {
+                    id: req.id.clone(),
+                    now: true,
+                    ..Default::default()
+                },
+            )?;
         }
-        let sock = sandboxes.remove(&req.id).unwrap();
-        let c = Client::connect(&sock)?;
-        let tc = TaskClient::new(c);
 
-        tc.shutdown(
-            context::Context::default(),
-            &api::ShutdownRequest {
-                id: req.id,
-                now: true,
-                ..Default::default()
-            },
-        )?;
+        // only forget about the sandbox once its tasks are shut down, so that a failed delete can be retried
+        let Some(sandbox) = self.sandboxes.write().unwrap().remove(&req.id) else {
+            return Err(Error::NotFound(req.id).into());
+        };
+        sandbox.server.shutdown();
+        let _ = remove_file(socket_path(&sandbox.socket_path));
+        self.remove_record(&req.id);
 
         Ok(sandbox::DeleteResponse::default())
     }
@@ -158,20 +362,70 @@ fn start_sandbox(cfg: runtime::Spec, server: &mut Server) -> Result<(), Error> {
 }
 
 /// Shim implements the containerd-shim CLI for connecting to a Manager service.
+///
+/// The `runtime_id` passed to `containerd_shim::run` is the name of the manager daemon binary,
+/// which is expected to live next to the shim binary. The shim starts the daemon if it's not running.
+#[derive(Clone)]
 pub struct Shim {
     id: String,
     namespace: String,
+    daemon: String,
+    address: String,
 }
 
 impl Task for Shim {}
 
+impl Shim {
+    fn manager_client(&self, start_daemon: bool) -> shim::Result<ManagerClient> {
+        let client = match Client::connect(&self.address) {
+            Ok(client) => client,
+            Err(_) if start_daemon => self.start_daemon()?,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(ManagerClient::new(client))
+    }
+
+    fn start_daemon(&self) -> shim::Result<Client> {
+        let exe = current_exe().map_err(|err| ShimError::Other(err.to_string()))?;
+        let daemon = exe.with_file_name(&self.daemon);
+        log::info!("starting manager daemon {}", daemon.display());
+
+        let mut command = Command::new(&daemon);
+        command
+            .args(["-socket", &self.address])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // detach from the shim, so that it outlives it
+        #[cfg(unix)]
+        command.process_group(0);
+        command.spawn().map_err(|err| {
+            ShimError::Other(format!("failed to start {}: {}", daemon.display(), err))
+        })?;
+
+        for _ in 0..DAEMON_START_RETRIES {
+            thread::sleep(DAEMON_START_INTERVAL);
+            if let Ok(client) = Client::connect(&self.address) {
+                return Ok(client);
+            }
+        }
+
+        Err(ShimError::Other(format!(
+            "timed out waiting for manager daemon on {}",
+            self.address
+        )))
+    }
+}
+
 impl shim::Shim for Shim {
     type T = Self;
 
-    fn new(_runtime_id: &str, args: &Flags, _config: &mut shim::Config) -> Self {
+    fn new(runtime_id: &str, args: &Flags, _config: &mut shim::Config) -> Self {
         Shim {
             id: args.id.to_string(),
             namespace: args.namespace.to_string(),
+            daemon: runtime_id.to_string(),
+            address: manager_address(),
         }
     }
 
@@ -189,15 +443,16 @@ impl shim::Shim for Shim {
             .unwrap_or(&opts.id)
             .to_string();
 
-        let client = Client::connect("unix:///run/io.containerd.wasmwasi.v1/manager.sock")?;
-        let mc = ManagerClient::new(client);
+        let mc = self.manager_client(true)?;
 
         let addr = match mc.create(
             context::Context::default(),
             &sandbox::CreateRequest {
                 id: sandbox.clone(),
+                namespace: opts.namespace.clone(),
                 working_directory: dir.as_path().to_str().unwrap().to_string(),
                 ttrpc_address: opts.ttrpc_address.clone(),
+                containerd_address: opts.address.clone(),
                 ..Default::default()
             },
         ) {
@@ -220,12 +475,12 @@ impl shim::Shim for Shim {
         Ok(addr)
     }
 
-    fn wait(&mut self) {
-        todo!()
-    }
+    // containerd talks to the task service of the sandbox in the manager daemon directly,
+    // so this shim never serves the task API itself.
+    fn wait(&mut self) {}
 
     fn create_task_service(&self, _publisher: RemotePublisher) -> Self::T {
-        todo!() // but not really, haha
+        self.clone()
     }
 
     fn delete_shim(&mut self) -> shim::Result<api::DeleteResponse> {
@@ -245,8 +500,10 @@ impl shim::Shim for Shim {
             return Ok(api::DeleteResponse::default());
         }
 
-        let client = Client::connect("unix:///run/io.containerd.wasmwasi.v1/manager.sock")?;
-        let mc = ManagerClient::new(client);
+        // if the daemon is not running there is no sandbox to delete
+        let Ok(mc) = self.manager_client(false) else {
+            return Ok(api::DeleteResponse::default());
+        };
         mc.delete(
             context::Context::default(),
             &sandbox::DeleteRequest {
@@ -0,0 +1,252 @@
+use std::fs::{create_dir, remove_dir_all, File};
+
+use containerd_shim::api::{CreateTaskRequest, StartRequest, StateRequest, Status};
+use containerd_shim::protos::ttrpc::Code;
+use tempfile::{tempdir, TempDir};
+
+use super::*;
+use crate::sandbox::instance::Nop;
+use crate::sandbox::shim::{task_state_dir, Local};
+use crate::services::sandbox_ttrpc::create_manager;
+
+// A server without any service, standing in for the containerd events socket.
+fn events_server(dir: &Path) -> (Server, String) {
+    let path = dir.join("events.sock");
+    let mut server = bind_server(&format!("unix://{}", path.display())).unwrap();
+    server.start().unwrap();
+    (server, path.to_str().unwrap().to_string())
+}
+
+fn manager_server(address: &str, state_dir: &Path) -> Server {
+    let _ = remove_file(socket_path(address));
+    let service = Service::<Local<Nop>>::new(()).with_state_dir(state_dir);
+    let service = Arc::new(Box::new(service) as Box<dyn Manager + Send + Sync>);
+    let mut server = bind_server(address)
+        .unwrap()
+        .register_service(create_manager(service));
+    server.start().unwrap();
+    server
+}
+
+fn create_bundle(dir: &Path) -> String {
+    create_dir(dir.join("rootfs")).unwrap();
+    serde_json::to_writer(
+        File::create(dir.join("config.json")).unwrap(),
+        &Spec::default(),
+    )
+    .unwrap();
+    dir.to_str().unwrap().to_string()
+}
+
+fn connect_request(id: &str) -> sandbox::ConnectRequest {
+    sandbox::ConnectRequest {
+        id: id.to_string(),
+        ..Default::default()
+    }
+}
+
+fn error_code(err: ttrpc::Error) -> Code {
+    match err {
+        ttrpc::Error::RpcStatus(status) => status.code(),
+        err => panic!("unexpected error: {err}"),
+    }
+}
+
+struct Setup {
+    temp: TempDir,
+    // unique per test, so that the tests don't share the task state of their sandboxes
+    namespace: String,
+    address: String,
+    state_dir: PathBuf,
+    _events: Server,
+    events_address: String,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let temp = tempdir().unwrap();
+        let namespace = temp
+            .path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let address = format!("unix://{}", temp.path().join("manager.sock").display());
+        let state_dir = temp.path().join("sandboxes");
+        let (_events, events_address) = events_server(temp.path());
+        Self {
+            temp,
+            namespace,
+            address,
+            state_dir,
+            _events,
+            events_address,
+        }
+    }
+
+    fn bundle(&self, id: &str) -> String {
+        let dir = self.temp.path().join(id);
+        create_dir(&dir).unwrap();
+        create_bundle(&dir)
+    }
+
+    fn client(&self) -> ManagerClient {
+        ManagerClient::new(Client::connect(&self.address).unwrap())
+    }
+
+    fn create_request(&self, id: &str, working_directory: &str) -> sandbox::CreateRequest {
+        sandbox::CreateRequest {
+            id: id.to_string(),
+            namespace: self.namespace.clone(),
+            working_directory: working_directory.to_string(),
+            ttrpc_address: self.events_address.clone(),
+            containerd_address: "/test/address".to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(task_state_dir(&self.namespace, "sandbox"));
+    }
+}
+
+#[test]
+fn test_create_connect_delete() {
+    let setup = Setup::new();
+    let server = manager_server(&setup.address, &setup.state_dir);
+    let mc = setup.client();
+    let bundle = setup.bundle("sandbox");
+    let ctx = context::Context::default;
+
+    let created = mc
+        .create(ctx(), &setup.create_request("sandbox", &bundle))
+        .unwrap();
+    assert_eq!(created.socket_path, format!("unix://{bundle}/shim.sock"));
+    assert!(setup.state_dir.join("sandbox.json").exists());
+
+    let err = mc
+        .create(ctx(), &setup.create_request("sandbox", &bundle))
+        .unwrap_err();
+    assert_eq!(error_code(err), Code::ALREADY_EXISTS);
+
+    let connected = mc.connect(ctx(), &connect_request("sandbox")).unwrap();
+    assert_eq!(connected.socket_path, created.socket_path);
+
+    let err = mc.connect(ctx(), &connect_request("unknown")).unwrap_err();
+    assert_eq!(error_code(err), Code::NOT_FOUND);
+
+    mc.delete(
+        ctx(),
+        &sandbox::DeleteRequest {
+            id: "sandbox".to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!(!setup.state_dir.join("sandbox.json").exists());
+    assert!(!socket_path(&created.socket_path).exists());
+
+    let err = mc.connect(ctx(), &connect_request("sandbox")).unwrap_err();
+    assert_eq!(error_code(err), Code::NOT_FOUND);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_sandbox_survives_daemon_restart() {
+    let setup = Setup::new();
+    let server = manager_server(&setup.address, &setup.state_dir);
+    let mc = setup.client();
+    let bundle = setup.bundle("sandbox");
+    let ctx = context::Context::default;
+
+    let created = mc
+        .create(ctx(), &setup.create_request("sandbox", &bundle))
+        .unwrap();
+
+    let tc = TaskClient::new(Client::connect(&created.socket_path).unwrap());
+    tc.create(
+        ctx(),
+        &CreateTaskRequest {
+            id: "task".to_string(),
+            bundle: bundle.clone(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let started = tc
+        .start(
+            ctx(),
+            &StartRequest {
+                id: "task".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    drop(tc);
+
+    // simulate a daemon restart
+    server.shutdown();
+    drop(mc);
+    let server = manager_server(&setup.address, &setup.state_dir);
+    let mc = setup.client();
+
+    let connected = mc.connect(ctx(), &connect_request("sandbox")).unwrap();
+    assert_eq!(connected.socket_path, created.socket_path);
+
+    let tc = TaskClient::new(Client::connect(&connected.socket_path).unwrap());
+    let state = tc
+        .state(
+            ctx(),
+            &StateRequest {
+                id: "task".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(state.status(), Status::RUNNING);
+    assert_eq!(state.pid, started.pid);
+    drop(tc);
+
+    mc.delete(
+        ctx(),
+        &sandbox::DeleteRequest {
+            id: "sandbox".to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    server.shutdown();
+}
+
+#[test]
+fn test_failed_delete_keeps_sandbox() {
+    let setup = Setup::new();
+    let server = manager_server(&setup.address, &setup.state_dir);
+    let mc = setup.client();
+    let bundle = setup.bundle("sandbox");
+    let ctx = context::Context::default;
+
+    let created = mc
+        .create(ctx(), &setup.create_request("sandbox", &bundle))
+        .unwrap();
+
+    // the manager can't reach the task service of the sandbox anymore
+    remove_file(socket_path(&created.socket_path)).unwrap();
+
+    let delete = sandbox::DeleteRequest {
+        id: "sandbox".to_string(),
+        ..Default::default()
+    };
+    mc.delete(ctx(), &delete).unwrap_err();
+
+    let connected = mc.connect(ctx(), &connect_request("sandbox")).unwrap();
+    assert_eq!(connected.socket_path, created.socket_path);
+    assert!(setup.state_dir.join("sandbox.json").exists());
+
+    server.shutdown();
+}
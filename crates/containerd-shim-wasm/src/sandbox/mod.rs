@@ -8,6 +8,7 @@ pub mod instance;
 pub mod instance_utils;
 pub mod manager;
 pub mod shim;
+pub mod signature;
 pub mod stdio;
 pub mod sync;
 
@@ -19,4 +20,4 @@ pub use stdio::Stdio;
 
 pub(crate) mod containerd;
 pub(crate) mod oci;
-pub use oci::WasmLayer;
+pub use oci::{LayerContent, PrecompiledArtifact, WasmLayer};
@@ -1,20 +1,176 @@
 //! Generic helpers for working with OCI specs that can be consumed by any runtime.
 
 use std::collections::HashMap;
-use std::io::{ErrorKind, Write};
+use std::fmt;
+use std::io::{Cursor, ErrorKind, Write};
+use std::ops::Deref;
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process;
+use std::sync::Arc;
 
 use anyhow::Context;
+use memmap2::Mmap;
 use oci_spec::image::Descriptor;
+use tempfile::{NamedTempFile, TempPath};
 
 use super::error::Result;
 
+/// Media type of the config of a wasm OCI artifact, as defined by the CNCF TAG Runtime
+/// <https://tag-runtime.cncf.io/wgs/wasm/deliverables/wasm-oci-artifact/>.
+pub const WASM_ARTIFACT_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v0+json";
+
+/// Media type of the layers of a wasm OCI artifact.
+pub const WASM_ARTIFACT_LAYER_MEDIA_TYPE: &str = "application/wasm";
+
 #[derive(Clone, Debug)]
 pub struct WasmLayer {
     pub config: Descriptor,
-    pub layer: Vec<u8>,
+    /// The content of the layer.
+    /// It dereferences to `[u8]` and converts from and into a `Vec<u8>`, like the byte vector it used to be.
+    pub layer: LayerContent,
+}
+
+/// The content of a [`WasmLayer`].
+///
+/// Layers read from the containerd content store are streamed to a temporary file that is
+/// memory mapped, so that the content is paged in on demand instead of being copied to the heap.
+/// Clones share the same content, and the file is removed when the last clone is dropped.
+#[derive(Clone, PartialEq)]
+pub struct LayerContent(Arc<Content>);
+
+enum Content {
+    Bytes(Vec<u8>),
+    Mapped { mmap: Mmap, path: TempPath },
+}
+
+impl PartialEq for Content {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Deref for Content {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Content::Bytes(bytes) => bytes,
+            Content::Mapped { mmap, .. } => mmap,
+        }
+    }
+}
+
+impl LayerContent {
+    /// Memory maps the content of a temporary file.
+    /// The file must not be modified once it has been mapped.
+    pub fn from_temp_file(file: NamedTempFile) -> std::io::Result<Self> {
+        // mapping an empty file is an error on some platforms
+        if file.as_file().metadata()?.len() == 0 {
+            return Ok(Self::from(vec![]));
+        }
+        // Safety: the file is private to this process and is not modified after this point
+        let mmap = unsafe { Mmap::map(file.as_file())? };
+        let path = file.into_temp_path();
+        Ok(Self(Arc::new(Content::Mapped { mmap, path })))
+    }
+
+    /// Returns the path of the file backing the content, if any.
+    /// Engines can use it to load precompiled content directly from disk.
+    pub fn path(&self) -> Option<&Path> {
+        match self.0.as_ref() {
+            Content::Bytes(_) => None,
+            Content::Mapped { path, .. } => Some(path),
+        }
+    }
+
+    /// Returns a reader over the content.
+    pub fn reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(self)
+    }
+}
+
+impl Deref for LayerContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for LayerContent {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for LayerContent {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Arc::new(Content::Bytes(bytes)))
+    }
+}
+
+impl From<&[u8]> for LayerContent {
+    fn from(bytes: &[u8]) -> Self {
+        Self::from(bytes.to_vec())
+    }
+}
+
+impl From<LayerContent> for Vec<u8> {
+    fn from(content: LayerContent) -> Self {
+        match Arc::try_unwrap(content.0) {
+            Ok(Content::Bytes(bytes)) => bytes,
+            Ok(content) => content.to_vec(),
+            Err(content) => content.to_vec(),
+        }
+    }
+}
+
+impl Default for LayerContent {
+    fn default() -> Self {
+        Self::from(vec![])
+    }
+}
+
+impl PartialEq<Vec<u8>> for LayerContent {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<[u8]> for LayerContent {
+    fn eq(&self, other: &[u8]) -> bool {
+        self[..] == *other
+    }
+}
+
+impl fmt::Debug for LayerContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayerContent")
+            .field("len", &self.len())
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+/// A precompiled artifact of a wasm layer in the containerd content store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrecompiledArtifact {
+    /// The name of the image the layer belongs to
+    pub image: String,
+    /// The digest of the original layer
+    pub layer: String,
+    /// The name of the engine that precompiled the layer
+    pub engine: String,
+    /// The `Engine::can_precompile` key the layer was precompiled with
+    pub key: String,
+    /// The digest of the precompiled content
+    pub digest: String,
+    /// The size of the precompiled content, in bytes
+    pub size: i64,
+    /// Whether the engine that precompiled the layer can no longer use it, because its key has changed
+    pub stale: bool,
 }
 
 fn parse_env(envs: &[String]) -> HashMap<String, String> {
@@ -101,3 +257,52 @@ pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) ->
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_layer_content_from_temp_file() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"some wasm")?;
+        let path = file.path().to_path_buf();
+
+        let content = LayerContent::from_temp_file(file)?;
+        assert_eq!(content, b"some wasm".to_vec());
+        assert_eq!(content.path(), Some(path.as_path()));
+
+        let mut read = vec![];
+        content.reader().read_to_end(&mut read)?;
+        assert_eq!(read, b"some wasm");
+
+        // the file is removed when the last clone is dropped
+        let clone = content.clone();
+        drop(content);
+        assert!(path.exists());
+        drop(clone);
+        assert!(!path.exists());
+
+        let empty = LayerContent::from_temp_file(NamedTempFile::new()?)?;
+        assert!(empty.is_empty());
+        assert_eq!(empty.path(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_content_converts_to_and_from_bytes() -> anyhow::Result<()> {
+        let content = LayerContent::from(b"some wasm".to_vec());
+        assert_eq!(Vec::from(content), b"some wasm");
+
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"some wasm")?;
+        let content = LayerContent::from_temp_file(file)?;
+        assert_eq!(Vec::from(content.clone()), b"some wasm");
+        assert_eq!(content.to_vec(), b"some wasm");
+
+        Ok(())
+    }
+}
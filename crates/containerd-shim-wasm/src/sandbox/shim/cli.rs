@@ -1,4 +1,5 @@
 use std::env::current_dir;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use chrono::Utc;
@@ -14,13 +15,22 @@ use crate::sandbox::shim::events::{RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::local::Local;
 use crate::sys::networking::setup_namespaces;
 
+// Directory where the shims persist the state of their tasks.
+static TASK_STATE_ROOT_DIR: &str = "/run/containerd/runwasi-tasks";
+
+/// Returns the directory where the shim `id` persists the state of its tasks.
+/// Unlike the bundle directories, it is not removed when one of the shim tasks is deleted.
+pub(crate) fn task_state_dir(namespace: &str, id: &str) -> PathBuf {
+    Path::new(TASK_STATE_ROOT_DIR).join(namespace).join(id)
+}
+
 /// Cli implements the containerd-shim cli interface using `Local<T>` as the task service.
 pub struct Cli<T: Instance + Sync + Send> {
     engine: T::Engine,
     namespace: String,
     containerd_address: String,
     exit: Arc<ExitSignal>,
-    _id: String,
+    id: String,
 }
 
 impl<I> shim::Shim for Cli<I>
@@ -36,7 +46,7 @@ where
             namespace: args.namespace.to_string(),
             containerd_address: args.address.clone(),
             exit: Arc::default(),
-            _id: args.id.to_string(),
+            id: args.id.to_string(),
         }
     }
 
@@ -71,6 +81,7 @@ where
         let events = RemoteEventSender::new(&self.namespace, publisher);
         let exit = self.exit.clone();
         let engine = self.engine.clone();
+        let state_dir = task_state_dir(&self.namespace, &self.id);
         Local::<I>::new(
             engine,
             events,
@@ -78,6 +89,7 @@ where
             &self.namespace,
             &self.containerd_address,
         )
+        .with_state_dir(state_dir)
     }
 
     fn delete_shim(&mut self) -> shim::Result<api::DeleteResponse> {
@@ -6,6 +6,7 @@ use chrono::{DateTime, Utc};
 use crate::sandbox::instance::Nop;
 use crate::sandbox::shim::instance_option::InstanceOption;
 use crate::sandbox::shim::task_state::TaskState;
+use crate::sandbox::shim::task_store::TaskRecord;
 use crate::sandbox::{Instance, InstanceConfig, Result};
 
 pub(super) struct InstanceData<T: Instance> {
@@ -13,6 +14,8 @@ pub(super) struct InstanceData<T: Instance> {
     cfg: InstanceConfig<T::Engine>,
     pid: OnceLock<u32>,
     state: Arc<RwLock<TaskState>>,
+    // exit status recorded by a previous shim process, see `InstanceData::restore`
+    exit: OnceLock<(u32, DateTime<Utc>)>,
 }
 
 impl<T: Instance> InstanceData<T> {
@@ -24,6 +27,7 @@ impl<T: Instance> InstanceData<T> {
             cfg,
             pid: OnceLock::default(),
             state: Arc::new(RwLock::new(TaskState::Created)),
+            exit: OnceLock::default(),
         })
     }
 
@@ -35,9 +39,67 @@ impl<T: Instance> InstanceData<T> {
             cfg,
             pid: OnceLock::default(),
             state: Arc::new(RwLock::new(TaskState::Created)),
+            exit: OnceLock::default(),
         })
     }
 
+    /// Rebuilds the instance data from a record persisted by a previous shim process.
+    pub fn restore(record: &TaskRecord, cfg: InstanceConfig<T::Engine>) -> Result<Self> {
+        let id = record.id.clone();
+        let instance = if record.base {
+            InstanceOption::Nop(Nop::reattach(id, None)?)
+        } else {
+            InstanceOption::Instance(T::reattach(id, Some(&cfg))?)
+        };
+
+        let pid = OnceLock::default();
+        if let Some(p) = record.pid {
+            let _ = pid.set(p);
+        }
+
+        let exit = OnceLock::default();
+        if let Some(e) = record.exit {
+            let _ = exit.set(e);
+        }
+
+        // A task that was in the middle of a transition when the shim went down
+        // can't complete it anymore, treat it as exited so that it can be deleted.
+        let state = match record.state {
+            TaskState::Starting | TaskState::Deleting => TaskState::Exited,
+            _ if record.exit.is_some() => TaskState::Exited,
+            state => state,
+        };
+
+        Ok(Self {
+            instance,
+            cfg,
+            pid,
+            state: Arc::new(RwLock::new(state)),
+            exit,
+        })
+    }
+
+    /// Returns the metadata to persist for this instance.
+    pub fn record(&self, id: impl AsRef<str>) -> TaskRecord {
+        // peek at the exit status without moving the task to the exited state
+        let exit = self
+            .exit
+            .get()
+            .copied()
+            .or_else(|| self.instance.wait_timeout(Duration::ZERO));
+        TaskRecord {
+            id: id.as_ref().to_string(),
+            bundle: self.cfg.get_bundle().to_path_buf(),
+            stdin: self.cfg.get_stdin().to_path_buf(),
+            stdout: self.cfg.get_stdout().to_path_buf(),
+            stderr: self.cfg.get_stderr().to_path_buf(),
+            base: matches!(self.instance, InstanceOption::Nop(_)),
+            pid: self.pid(),
+            state: *self.state.read().unwrap(),
+            exit,
+        }
+    }
+
     pub fn pid(&self) -> Option<u32> {
         self.pid.get().copied()
     }
@@ -87,14 +149,20 @@ impl<T: Instance> InstanceData<T> {
     }
 
     pub fn wait(&self) -> (u32, DateTime<Utc>) {
-        let res = self.instance.wait();
+        let res = match self.exit.get() {
+            Some(res) => *res,
+            None => self.instance.wait(),
+        };
         let mut s = self.state.write().unwrap();
         *s = TaskState::Exited;
         res
     }
 
     pub fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
-        let res = self.instance.wait_timeout(t);
+        let res = match self.exit.get() {
+            Some(res) => Some(*res),
+            None => self.instance.wait_timeout(t),
+        };
         if res.is_some() {
             let mut s = self.state.write().unwrap();
             *s = TaskState::Exited;
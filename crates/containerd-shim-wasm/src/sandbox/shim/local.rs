@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::ErrorKind;
 use std::ops::Not;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Duration;
@@ -25,6 +26,9 @@ use oci_spec::runtime::Spec;
 use crate::sandbox::instance::{Instance, InstanceConfig};
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::shim::task_state::TaskState;
+use crate::sandbox::shim::task_state_dir;
+use crate::sandbox::shim::task_store::TaskStore;
 use crate::sandbox::{oci, Error, Result, SandboxService};
 use crate::sys::metrics::get_metrics;
 
@@ -33,6 +37,9 @@ mod tests;
 
 type LocalInstances<T> = RwLock<HashMap<String, Arc<InstanceData<T>>>>;
 
+// Directory, relative to the state directory, where the wasm layers of the tasks are stored.
+const LAYERS_DIR: &str = "layers";
+
 /// Local implements the Task service for a containerd shim.
 /// It defers all task operations to the `Instance` implementation.
 pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender> {
@@ -42,6 +49,8 @@ pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender>
     exit: Arc<ExitSignal>,
     namespace: String,
     containerd_address: String,
+    store: TaskStore,
+    layers_dir: Option<PathBuf>,
 }
 
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
@@ -63,9 +72,108 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             exit,
             namespace,
             containerd_address,
+            store: TaskStore::default(),
+            layers_dir: None,
+        }
+    }
+
+    /// Persists the task state in `dir`, and restores any task saved there by a previous shim process.
+    /// Restored tasks keep serving `Connect`, `State` and `Wait` after a shim restart.
+    /// The wasm layers of the tasks are stored in `dir` as well.
+    pub fn with_state_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.store = TaskStore::new(&dir);
+        self.layers_dir = Some(dir.as_ref().join(LAYERS_DIR));
+        // the layers of a previous shim process are not used by anyone anymore
+        self.remove_layers_dir();
+        self.restore();
+        self
+    }
+
+    fn remove_layers_dir(&self) {
+        let Some(dir) = &self.layers_dir else {
+            return;
+        };
+        match remove_dir_all(dir) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                log::warn!("could not remove wasm layers in {dir:?}: {err}");
+            }
+            _ => {}
+        }
+    }
+
+    fn restore(&self) {
+        let records = match self.store.load_all() {
+            Ok(records) => records,
+            Err(err) => {
+                log::warn!("could not load persisted task state: {err}");
+                return;
+            }
+        };
+
+        for record in records {
+            let mut cfg = self.instance_config();
+            cfg.set_bundle(&record.bundle)
+                .set_stdin(&record.stdin)
+                .set_stdout(&record.stdout)
+                .set_stderr(&record.stderr);
+
+            let instance = match InstanceData::restore(&record, cfg) {
+                Ok(instance) => Arc::new(instance),
+                Err(err) => {
+                    log::warn!("could not restore task {}: {err}", record.id);
+                    continue;
+                }
+            };
+
+            debug!("restored task {}: {:?}", record.id, record.state);
+            self.instances
+                .write()
+                .unwrap()
+                .insert(record.id.clone(), instance.clone());
+
+            if matches!(record.state, TaskState::Started) && record.exit.is_none() {
+                let pid = record.pid.unwrap_or_default();
+                if let Err(err) = self.spawn_exit_watcher(record.id.clone(), instance, pid) {
+                    log::warn!("could not watch restored task {}: {err}", record.id);
+                }
+            }
         }
     }
 
+    fn persist(&self, id: &str, instance: &InstanceData<T>) {
+        if let Err(err) = self.store.save(&instance.record(id)) {
+            log::warn!("could not persist state of task {id}: {err}");
+        }
+    }
+
+    // Waits for the task to exit in a background thread, then publishes the `TaskExit` event
+    // and persists the exit status.
+    fn spawn_exit_watcher(&self, id: String, i: Arc<InstanceData<T>>, pid: u32) -> Result<()> {
+        let events = self.events.clone();
+        let store = self.store.clone();
+
+        thread::Builder::new()
+            .name(format!("{id}-wait"))
+            .spawn(move || {
+                let (exit_code, timestamp) = i.wait();
+                if let Err(err) = store.save(&i.record(&id)) {
+                    log::warn!("could not persist state of task {id}: {err}");
+                }
+                events.send(TaskExit {
+                    container_id: id.clone(),
+                    exit_status: exit_code,
+                    exited_at: Some(timestamp.to_timestamp()).into(),
+                    pid,
+                    id,
+                    ..Default::default()
+                });
+            })
+            .context("could not spawn thread to wait exit")
+            .map_err(Error::from)?;
+
+        Ok(())
+    }
+
     pub(super) fn get_instance(&self, id: &str) -> Result<Arc<InstanceData<T>>> {
         let instance = self.instances.read().unwrap().get(id).cloned();
         instance.ok_or_else(|| Error::NotFound(id.to_string()))
@@ -80,11 +188,15 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     }
 
     fn instance_config(&self) -> InstanceConfig<T::Engine> {
-        InstanceConfig::new(
+        let mut cfg = InstanceConfig::new(
             self.engine.clone(),
             &self.namespace,
             &self.containerd_address,
-        )
+        );
+        if let Some(dir) = &self.layers_dir {
+            cfg.set_layers_dir(dir);
+        }
+        cfg
     }
 }
 
@@ -156,6 +268,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             InstanceData::new_instance(req.id(), cfg)?
         };
 
+        self.persist(req.id(), &instance);
         self.instances
             .write()
             .unwrap()
@@ -194,6 +307,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
         let i = self.get_instance(req.id())?;
         let pid = i.start()?;
+        self.persist(req.id(), &i);
 
         self.events.send(TaskStart {
             container_id: req.id().into(),
@@ -201,25 +315,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             ..Default::default()
         });
 
-        let events = self.events.clone();
-
-        let id = req.id().to_string();
-
-        thread::Builder::new()
-            .name(format!("{id}-wait"))
-            .spawn(move || {
-                let (exit_code, timestamp) = i.wait();
-                events.send(TaskExit {
-                    container_id: id.clone(),
-                    exit_status: exit_code,
-                    exited_at: Some(timestamp.to_timestamp()).into(),
-                    pid,
-                    id,
-                    ..Default::default()
-                });
-            })
-            .context("could not spawn thread to wait exit")
-            .map_err(Error::from)?;
+        self.spawn_exit_watcher(req.id().to_string(), i, pid)?;
 
         debug!("started: {:?}", req);
 
@@ -251,6 +347,12 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
         self.instances.write().unwrap().remove(req.id());
+        if let Err(err) = self.store.remove(req.id()) {
+            log::warn!(
+                "could not remove persisted state of task {}: {err}",
+                req.id()
+            );
+        }
 
         self.events.send(TaskDelete {
             container_id: req.id().into(),
@@ -334,13 +436,15 @@ impl<T: Instance + Sync + Send> SandboxService for Local<T, RemoteEventSender> {
     fn new(
         namespace: String,
         containerd_address: String,
-        _id: String,
+        id: String,
         engine: T::Engine,
         publisher: RemotePublisher,
     ) -> Self {
         let events = RemoteEventSender::new(&namespace, publisher);
         let exit = Arc::default();
+        let state_dir = task_state_dir(&namespace, &id);
         Local::<T>::new(engine, events, exit, namespace, containerd_address)
+            .with_state_dir(state_dir)
     }
 }
 
@@ -390,6 +494,10 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
     fn shutdown(&self, _: &TtrpcContext, _: ShutdownRequest) -> TtrpcResult<Empty> {
         debug!("shutdown");
         if self.is_empty() {
+            self.remove_layers_dir();
+            if let Err(err) = self.store.remove_dir() {
+                log::warn!("could not remove task state directory: {err}");
+            }
             self.exit.signal();
         }
         Ok(Empty::new())
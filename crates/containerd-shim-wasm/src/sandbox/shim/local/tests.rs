@@ -14,6 +14,7 @@ use super::*;
 use crate::sandbox::instance::Nop;
 use crate::sandbox::shim::events::EventSender;
 use crate::sandbox::shim::instance_option::InstanceOption;
+use crate::sandbox::shim::task_store::TaskStore;
 
 struct LocalWithDescrutor<T: Instance + Send + Sync, E: EventSender> {
     local: Arc<Local<T, E>>,
@@ -392,3 +393,194 @@ fn test_task_lifecycle() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_task_state_survives_restart() -> Result<()> {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+    let state_dir = temp.path().join("state");
+
+    let (etx, _erx) = channel();
+    let local = Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    )
+    .with_state_dir(&state_dir);
+
+    local.task_create(CreateTaskRequest {
+        id: "test".to_string(),
+        bundle: dir.to_str().unwrap().to_string(),
+        stdout: "/test/stdout".to_string(),
+        ..Default::default()
+    })?;
+
+    let started = local.task_start(StartRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+
+    // simulate a shim restart by dropping the task service without cleaning up
+    drop(local);
+
+    let (etx, erx) = channel();
+    let local = Arc::new(
+        Local::<Nop, _>::new(
+            (),
+            etx,
+            Arc::new(ExitSignal::default()),
+            "test_namespace",
+            "/test/address",
+        )
+        .with_state_dir(&state_dir),
+    );
+    let mut _wrapped = LocalWithDescrutor::new(local.clone());
+
+    let state = local.task_state(StateRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(state.status(), Status::RUNNING);
+    assert_eq!(state.pid, started.pid);
+    assert_eq!(state.bundle, dir.to_str().unwrap());
+    assert_eq!(state.stdout, "/test/stdout");
+
+    let (tx, rx) = channel();
+    let ll = local.clone();
+    thread::spawn(move || {
+        let resp = ll.task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        });
+        tx.send(resp).unwrap();
+    });
+    rx.try_recv().unwrap_err();
+
+    local.task_kill(KillRequest {
+        id: "test".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+
+    let resp = rx.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert_eq!(resp.exit_status, 137);
+
+    // the restored task publishes its exit event
+    let (topic, _) = erx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(topic, TaskExit::default().topic());
+
+    local.task_delete(DeleteRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+
+    assert!(TaskStore::new(&state_dir).load_all()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_exited_task_survives_restart() -> Result<()> {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+    let state_dir = temp.path().join("state");
+
+    let (etx, _erx) = channel();
+    let local = Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    )
+    .with_state_dir(&state_dir);
+
+    local.task_create(CreateTaskRequest {
+        id: "test".to_string(),
+        bundle: dir.to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+    local.task_start(StartRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+    local.task_kill(KillRequest {
+        id: "test".to_string(),
+        signal: 9,
+        ..Default::default()
+    })?;
+    local.task_wait(WaitRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+
+    // give the exit watcher a chance to persist the exit status
+    let store = TaskStore::new(&state_dir);
+    for _ in 0..50 {
+        if store.load_all()?.iter().all(|r| r.exit.is_some()) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(local);
+
+    let (etx, _erx) = channel();
+    let local = Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    )
+    .with_state_dir(&state_dir);
+
+    let state = local.task_state(StateRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(state.status(), Status::STOPPED);
+    assert_eq!(state.exit_status, 137);
+
+    let resp = local.task_wait(WaitRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(resp.exit_status, 137);
+
+    local.task_delete(DeleteRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    })?;
+    assert!(store.load_all()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_layers_are_stored_in_the_state_dir() -> Result<()> {
+    let temp = tempdir().unwrap();
+    let state_dir = temp.path().join("state");
+    let layers_dir = state_dir.join(LAYERS_DIR);
+
+    // layers left behind by a shim that crashed
+    create_dir_all(&layers_dir)?;
+    File::create(layers_dir.join("runwasi-layer-1234"))?;
+
+    let (etx, _erx) = channel();
+    let local = Local::<Nop, _>::new(
+        (),
+        etx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    )
+    .with_state_dir(&state_dir);
+    assert!(!layers_dir.exists());
+    assert_eq!(local.instance_config().get_layers_dir(), Some(&*layers_dir));
+
+    Ok(())
+}
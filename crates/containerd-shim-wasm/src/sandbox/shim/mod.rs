@@ -8,6 +8,8 @@ mod instance_data;
 mod instance_option;
 mod local;
 mod task_state;
+mod task_store;
 
+pub(crate) use cli::task_state_dir;
 pub use cli::Cli;
 pub(crate) use local::Local;
@@ -1,7 +1,9 @@
+use serde::{Deserialize, Serialize};
+
 use crate::sandbox::Error::FailedPrecondition;
 use crate::sandbox::Result;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub(super) enum TaskState {
     Created,
     Starting,
@@ -0,0 +1,165 @@
+//! Persistence of task metadata so that a restarted shim can rebuild its
+//! `InstanceData` and keep serving `Connect`, `State` and `Wait`.
+
+use std::fs::{create_dir_all, read_dir, remove_dir, remove_file, rename, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::shim::task_state::TaskState;
+use crate::sandbox::Result;
+
+/// The metadata of a task as stored on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct TaskRecord {
+    pub id: String,
+    pub bundle: PathBuf,
+    pub stdin: PathBuf,
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    /// Whether this is the "pause" container of a cri sandbox, see `InstanceData::new_base`
+    pub base: bool,
+    pub pid: Option<u32>,
+    pub state: TaskState,
+    pub exit: Option<(u32, DateTime<Utc>)>,
+}
+
+/// TaskStore saves one json file per task in a state directory.
+/// A store without a directory is a no-op, which is what `Local::new` uses.
+#[derive(Clone, Default)]
+pub(super) struct TaskStore {
+    dir: Option<PathBuf>,
+}
+
+impl TaskStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        let dir = Some(dir.as_ref().to_path_buf());
+        Self { dir }
+    }
+
+    pub fn save(&self, record: &TaskRecord) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        create_dir_all(dir)?;
+
+        // write to a temporary file first so that a crash never leaves a truncated record behind
+        let path = record_path(dir, &record.id);
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, record)?;
+        rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        match remove_file(record_path(dir, id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the state directory once it holds no more tasks.
+    pub fn remove_dir(&self) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        match remove_dir(dir) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn load_all(&self) -> Result<Vec<TaskRecord>> {
+        let Some(dir) = &self.dir else {
+            return Ok(vec![]);
+        };
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut records = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let record = serde_json::from_reader(File::open(&path)?)
+                    .with_context(|| format!("could not parse task record {path:?}"))?;
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn record_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn record(id: &str) -> TaskRecord {
+        TaskRecord {
+            id: id.to_string(),
+            bundle: PathBuf::from("/bundle"),
+            stdin: PathBuf::from("/stdin"),
+            stdout: PathBuf::from("/stdout"),
+            stderr: PathBuf::from("/stderr"),
+            base: false,
+            pid: Some(42),
+            state: TaskState::Started,
+            exit: None,
+        }
+    }
+
+    #[test]
+    fn test_save_load_remove() -> Result<()> {
+        let dir = tempdir()?;
+        let store = TaskStore::new(dir.path().join("tasks"));
+
+        assert!(store.load_all()?.is_empty());
+
+        store.save(&record("a"))?;
+        store.save(&record("b"))?;
+
+        let mut records = store.load_all()?;
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "a");
+        assert_eq!(records[0].pid, Some(42));
+        assert!(matches!(records[0].state, TaskState::Started));
+
+        store.remove("a")?;
+        store.remove("a")?;
+        let records = store.load_all()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "b");
+
+        store.remove("b")?;
+        store.remove_dir()?;
+        assert!(!dir.path().join("tasks").exists());
+        store.remove_dir()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_without_dir_is_noop() -> Result<()> {
+        let store = TaskStore::default();
+        store.save(&record("a"))?;
+        assert!(store.load_all()?.is_empty());
+        store.remove("a")?;
+        store.remove_dir()?;
+        Ok(())
+    }
+}
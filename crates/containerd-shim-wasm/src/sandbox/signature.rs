@@ -0,0 +1,283 @@
+//! Verification of detached signatures of wasm OCI images against a local trust store.
+//!
+//! An image is signed by adding one or more layers of type [`SIGNATURE_LAYER_MEDIA_TYPE`] to it.
+//! Each of them contains a [`Signature`] of the [`signing_payload`] of the config and the other layers of the image.
+//! When the `RUNWASI_TRUST_STORE` environment variable points to a directory of PEM encoded public keys,
+//! only images with a signature from one of those keys are run, whatever their platform.
+//! Ed25519 and ECDSA P-256 (as used by cosign) keys are supported.
+
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use ring::signature::{UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ED25519};
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::{Error, Result};
+
+/// Media type of the layers containing a detached [`Signature`] of an image.
+pub const SIGNATURE_LAYER_MEDIA_TYPE: &str = "application/vnd.runwasi.wasm.signature.v1+json";
+
+/// Environment variable with the path of the trust store directory.
+pub const TRUST_STORE_ENV: &str = "RUNWASI_TRUST_STORE";
+
+// DER prefixes of the SubjectPublicKeyInfo of the supported keys, followed by the raw key
+const ED25519_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// The content of a signature layer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Signature {
+    /// The base64 encoded signature of the signing payload
+    pub signature: String,
+}
+
+/// Returns the payload signed by the signature layers of an image:
+/// the digest of the image config followed by the digests of all the other layers of the image,
+/// one per line, in the manifest order.
+pub fn signing_payload<'a>(
+    config_digest: &'a str,
+    layer_digests: impl IntoIterator<Item = &'a str>,
+) -> Vec<u8> {
+    std::iter::once(config_digest)
+        .chain(layer_digests)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .into_bytes()
+}
+
+struct PublicKey {
+    name: String,
+    algorithm: &'static dyn VerificationAlgorithm,
+    bytes: Vec<u8>,
+}
+
+/// TrustStore holds the public keys that images have to be signed with.
+pub struct TrustStore {
+    keys: Vec<PublicKey>,
+}
+
+impl TrustStore {
+    /// Loads the trust store from the directory in `RUNWASI_TRUST_STORE`.
+    /// Returns `None` if the variable is not set, in which case images are not verified.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var_os(TRUST_STORE_ENV) {
+            Some(dir) => Self::load(dir).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads all the PEM encoded public keys in `dir`.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut keys = vec![];
+        let failed = |err: std::io::Error| {
+            Error::FailedPrecondition(format!("failed to read trust store {dir:?}: {err}"))
+        };
+        for entry in read_dir(dir).map_err(failed)? {
+            let path = entry.map_err(failed)?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let name = path.display().to_string();
+            let key = parse_public_key(name, &read_to_string(&path).map_err(failed)?)?;
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            return Err(Error::FailedPrecondition(format!(
+                "trust store {dir:?} doesn't contain any key"
+            )));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Verifies that at least one of the signatures is a signature of `payload` by a trusted key.
+    pub fn verify(&self, payload: &[u8], signatures: &[Signature]) -> Result<()> {
+        if signatures.is_empty() {
+            return Err(Error::FailedPrecondition(
+                "image is not signed, refusing to run it".to_string(),
+            ));
+        }
+        for signature in signatures {
+            let Ok(signature) = BASE64.decode(&signature.signature) else {
+                log::warn!("ignoring signature that is not valid base64");
+                continue;
+            };
+            for key in &self.keys {
+                let public_key = UnparsedPublicKey::new(key.algorithm, &key.bytes);
+                if public_key.verify(payload, &signature).is_ok() {
+                    log::info!("image signature verified with key {}", key.name);
+                    return Ok(());
+                }
+            }
+        }
+        Err(Error::FailedPrecondition(
+            "no valid signature from a trusted key, refusing to run the image".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+impl TrustStore {
+    // a trust store with a single Ed25519 key, for the tests of the image verification
+    pub(crate) fn with_ed25519_key(public_key: &[u8]) -> Self {
+        let key = PublicKey {
+            name: "test".to_string(),
+            algorithm: &ED25519,
+            bytes: public_key.to_vec(),
+        };
+        Self { keys: vec![key] }
+    }
+}
+
+fn parse_public_key(name: String, pem: &str) -> Result<PublicKey> {
+    let invalid = |reason: &str| Error::FailedPrecondition(format!("invalid key {name}: {reason}"));
+
+    let base64: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "-----BEGIN PUBLIC KEY-----")
+        .skip(1)
+        .take_while(|line| *line != "-----END PUBLIC KEY-----")
+        .collect();
+    let der = BASE64
+        .decode(base64)
+        .map_err(|_| invalid("not a PEM encoded public key"))?;
+
+    let (algorithm, bytes): (&'static dyn VerificationAlgorithm, _) =
+        if let Some(bytes) = der.strip_prefix(ED25519_SPKI_PREFIX) {
+            (&ED25519, bytes)
+        } else if let Some(bytes) = der.strip_prefix(P256_SPKI_PREFIX) {
+            (&ECDSA_P256_SHA256_ASN1, bytes)
+        } else {
+            return Err(invalid("only Ed25519 and ECDSA P-256 keys are supported"));
+        };
+
+    Ok(PublicKey {
+        name,
+        algorithm,
+        bytes: bytes.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn to_pem(prefix: &[u8], public_key: &[u8]) -> String {
+        let der = [prefix, public_key].concat();
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            BASE64.encode(der)
+        )
+    }
+
+    fn signature(bytes: impl AsRef<[u8]>) -> Signature {
+        Signature {
+            signature: BASE64.encode(bytes),
+        }
+    }
+
+    fn ed25519_key() -> Ed25519KeyPair {
+        let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(doc.as_ref()).unwrap()
+    }
+
+    fn p256_key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let doc = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, doc.as_ref(), &rng).unwrap()
+    }
+
+    #[test]
+    fn test_verify_signatures() -> Result<()> {
+        let dir = tempdir()?;
+        let ed25519 = ed25519_key();
+        let p256 = p256_key();
+        write(
+            dir.path().join("ed25519.pub"),
+            to_pem(ED25519_SPKI_PREFIX, ed25519.public_key().as_ref()),
+        )?;
+        write(
+            dir.path().join("p256.pub"),
+            to_pem(P256_SPKI_PREFIX, p256.public_key().as_ref()),
+        )?;
+        let store = TrustStore::load(dir.path())?;
+
+        let payload = signing_payload("sha256:abcd", ["sha256:1234", "sha256:5678"]);
+        assert_eq!(payload, b"sha256:abcd\nsha256:1234\nsha256:5678");
+
+        store.verify(&payload, &[signature(ed25519.sign(&payload))])?;
+
+        let p256_signature = p256.sign(&SystemRandom::new(), &payload).unwrap();
+        store.verify(&payload, &[signature(p256_signature)])?;
+
+        // one valid signature is enough
+        store.verify(
+            &payload,
+            &[signature(b"garbage"), signature(ed25519.sign(&payload))],
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_unsigned_or_tampered_images() -> Result<()> {
+        let dir = tempdir()?;
+        let trusted = ed25519_key();
+        let untrusted = ed25519_key();
+        write(
+            dir.path().join("trusted.pub"),
+            to_pem(ED25519_SPKI_PREFIX, trusted.public_key().as_ref()),
+        )?;
+        let store = TrustStore::load(dir.path())?;
+
+        let payload = signing_payload("sha256:abcd", ["sha256:1234"]);
+
+        let err = store.verify(&payload, &[]).unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)));
+
+        let tampered = signing_payload("sha256:abcd", ["sha256:4321"]);
+        let err = store
+            .verify(&tampered, &[signature(trusted.sign(&payload))])
+            .unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)));
+
+        let tampered_config = signing_payload("sha256:dcba", ["sha256:1234"]);
+        let err = store
+            .verify(&tampered_config, &[signature(trusted.sign(&payload))])
+            .unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)));
+
+        let err = store
+            .verify(&payload, &[signature(untrusted.sign(&payload))])
+            .unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_trust_store() -> Result<()> {
+        let dir = tempdir()?;
+        assert!(TrustStore::load(dir.path()).is_err());
+        assert!(TrustStore::load(dir.path().join("missing")).is_err());
+
+        write(dir.path().join("key.pub"), "not a key")?;
+        assert!(TrustStore::load(dir.path()).is_err());
+
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use std::cell::OnceCell;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::unix::prelude::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use libcontainer::workload::default::DefaultExecutor;
@@ -30,6 +31,8 @@ pub(crate) struct Executor<E: Engine> {
     inner: OnceCell<InnerExecutor>,
     wasm_layers: Vec<WasmLayer>,
     platform: Platform,
+    // file of the shim the exit status of the wasm container is written to, see `Instance::reattach`
+    exit_status: Arc<File>,
 }
 
 impl<E: Engine> LibcontainerExecutor for Executor<E> {
@@ -53,26 +56,39 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
             }
             InnerExecutor::Wasm => {
                 log::info!("calling start function");
-                match self.engine.run_wasi(&self.ctx(spec), self.stdio.take()) {
-                    Ok(code) => std::process::exit(code),
+                let code = match self.engine.run_wasi(&self.ctx(spec), self.stdio.take()) {
+                    Ok(code) => code,
                     Err(err) => {
                         log::info!("error running start function: {err}");
-                        std::process::exit(137)
+                        137
                     }
                 };
+                // the status the process exits with, as its parent sees it
+                let status = code & 0xff;
+                if let Err(err) = (&*self.exit_status).write_all(status.to_string().as_bytes()) {
+                    log::warn!("could not persist the exit status: {err}");
+                }
+                std::process::exit(code)
             }
         }
     }
 }
 
 impl<E: Engine> Executor<E> {
-    pub fn new(engine: E, stdio: Stdio, wasm_layers: Vec<WasmLayer>, platform: Platform) -> Self {
+    pub fn new(
+        engine: E,
+        stdio: Stdio,
+        wasm_layers: Vec<WasmLayer>,
+        platform: Platform,
+        exit_status: File,
+    ) -> Self {
         Self {
             engine,
             stdio,
             inner: Default::default(),
             wasm_layers,
             platform,
+            exit_status: Arc::new(exit_status),
         }
     }
 
@@ -90,10 +106,14 @@ impl<E: Engine> Executor<E> {
         self.inner.get_or_init(|| {
             if is_linux_container(&self.ctx(spec)).is_ok() {
                 InnerExecutor::Linux
-            } else if self.engine.can_handle(&self.ctx(spec)).is_ok() {
-                InnerExecutor::Wasm
             } else {
-                InnerExecutor::CantHandle
+                match self.engine.can_handle(&self.ctx(spec)) {
+                    Ok(()) => InnerExecutor::Wasm,
+                    Err(err) => {
+                        log::error!("{} can't handle the container: {err:#}", E::name());
+                        InnerExecutor::CantHandle
+                    }
+                }
             }
         })
     }
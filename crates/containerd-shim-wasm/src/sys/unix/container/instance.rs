@@ -1,3 +1,4 @@
+use std::fs::{self, File};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::thread;
@@ -6,27 +7,39 @@ use std::time::Duration;
 use anyhow::Context;
 use chrono::{DateTime, Utc};
 use libcontainer::container::builder::ContainerBuilder;
-use libcontainer::container::Container;
+use libcontainer::container::{Container, ContainerStatus};
 use libcontainer::signal::Signal;
 use libcontainer::syscall::syscall::SyscallType;
 use nix::errno::Errno;
+use nix::sys::signal::kill;
 use nix::sys::wait::{waitid, Id as WaitID, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use oci_spec::image::Platform;
+use oci_spec::runtime::Spec;
 
-use crate::container::Engine;
+use crate::container::{Engine, WasiContext};
 use crate::sandbox::instance_utils::{determine_rootdir, get_instance_root, instance_exists};
 use crate::sandbox::sync::WaitableCell;
 use crate::sandbox::{
-    containerd, Error as SandboxError, Instance as SandboxInstance, InstanceConfig, Stdio,
+    containerd, Error as SandboxError, Instance as SandboxInstance, InstanceConfig,
+    PrecompiledArtifact, Stdio, WasmLayer,
 };
 use crate::sys::container::executor::Executor;
 
 static DEFAULT_CONTAINER_ROOT_DIR: &str = "/run/containerd";
+static REATTACH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// File of the bundle the init process of a wasm container writes its exit status to before exiting,
+// for the shim processes that can't collect it because they reattached to the container after a restart.
+static EXIT_STATUS_FILE: &str = "exit_status";
+
+// Exit status of the containers whose exit status is lost, the one containerd reports for unknown statuses.
+const UNKNOWN_EXIT_STATUS: u32 = 255;
 
 pub struct Instance<E: Engine> {
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
     rootdir: PathBuf,
+    bundle: PathBuf,
     id: String,
     _phantom: PhantomData<E>,
 }
@@ -44,15 +57,35 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         let stdio = Stdio::init_from_cfg(cfg)?;
 
         // check if container is OCI image with wasm layers and attempt to read the module
-        let (modules, platform) = containerd::Client::connect(cfg.get_containerd_address().as_str(), &namespace)?
-            .load_modules(&id, &engine)
-            .unwrap_or_else(|e| {
-                log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                (vec![], Platform::default())
-            });
+        let (modules, platform) = load_modules(&id, cfg)?;
+
+        if let Err(err) = engine.prepare(&modules) {
+            log::warn!("Error preparing wasm layers for container {id}: {err}");
+        }
+
+        // Wasm layers don't depend on the container filesystem, so they are checked here as well as in the
+        // container, to report the reason the engine can't run them when the container is created.
+        if !modules.is_empty() {
+            let spec = Spec::load(bundle.join("config.json"))
+                .context("failed to load the container spec")?;
+            let ctx = WasiContext {
+                spec: &spec,
+                wasm_layers: &modules,
+                platform: &platform,
+            };
+            engine.can_handle(&ctx).map_err(|err| {
+                SandboxError::InvalidArgument(format!(
+                    "{} can't run the container: {err:#}",
+                    E::name()
+                ))
+            })?;
+        }
+
+        let exit_status = File::create(bundle.join(EXIT_STATUS_FILE))
+            .context("failed to create the exit status file")?;
 
         ContainerBuilder::new(id.clone(), SyscallType::Linux)
-            .with_executor(Executor::new(engine, stdio, modules, platform))
+            .with_executor(Executor::new(engine, stdio, modules, platform, exit_status))
             .with_root_path(rootdir.clone())?
             .as_init(&bundle)
             .with_systemd(false)
@@ -62,10 +95,93 @@ impl<E: Engine> SandboxInstance for Instance<E> {
             id,
             exit_code: WaitableCell::new(),
             rootdir,
+            bundle,
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Reattach to a container created by a previous shim process.
+    /// The container keeps running in the background while the shim is down,
+    /// so we only need to load its state and watch its init process again.
+    ///
+    /// The init process is not a child of this shim anymore, so its exit status can't be collected:
+    /// it's read from the file the init process of wasm containers writes it to before exiting,
+    /// and reported as unknown (255) for the containers that didn't write it, e.g. when they are killed.
+    fn reattach(
+        id: String,
+        cfg: Option<&InstanceConfig<Self::Engine>>,
+    ) -> Result<Self, SandboxError> {
+        let cfg = cfg.context("missing configuration")?;
+        let bundle = cfg.get_bundle().to_path_buf();
+        let namespace = cfg.get_namespace();
+        let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
+        let rootdir = determine_rootdir(&bundle, &namespace, rootdir)?;
+
+        let container_root = get_instance_root(&rootdir, &id)?;
+        let container = Container::load(container_root)
+            .with_context(|| format!("could not load state for container {id}"))?;
+
+        let exit_code = WaitableCell::new();
+        match (container.status(), container.pid()) {
+            // the init process waits to be started, it's watched once the container is started
+            (ContainerStatus::Created, Some(_)) => {
+                log::info!("reattaching to created instance: {id}");
+            }
+            (ContainerStatus::Running | ContainerStatus::Paused, Some(pid)) => {
+                log::info!("reattaching to running instance: {id}");
+                let exit_code = exit_code.clone();
+                let bundle = bundle.clone();
+                thread::spawn(move || {
+                    let status = poll_for_exit(pid.as_raw(), &bundle);
+                    let _ = exit_code.set((status, Utc::now()));
+                });
+            }
+            // the container stopped while the shim was down, or never got an init process
+            (status, _) => {
+                log::info!("reattaching to {status:?} instance: {id}");
+                let _ = exit_code.set((read_exit_status(&bundle), Utc::now()));
+            }
+        }
+
+        Ok(Self {
+            id,
+            exit_code,
+            rootdir,
+            bundle,
             _phantom: Default::default(),
         })
     }
 
+    fn precompile_image(
+        engine: &Self::Engine,
+        containerd_address: &str,
+        namespace: &str,
+        image: &str,
+    ) -> Result<(), SandboxError> {
+        containerd::Client::connect(containerd_address, namespace)?.precompile_image(image, engine)
+    }
+
+    fn precompiled_artifacts(
+        engine: &Self::Engine,
+        containerd_address: &str,
+        namespace: &str,
+        remove_stale: bool,
+    ) -> Result<Vec<PrecompiledArtifact>, SandboxError> {
+        let client = containerd::Client::connect(containerd_address, namespace)?;
+        let artifacts = client.precompiled_artifacts(engine)?;
+        if remove_stale {
+            for artifact in artifacts.iter().filter(|a| a.stale) {
+                log::info!("removing stale precompiled content {}", artifact.digest);
+                client.remove_precompiled_artifact(artifact)?;
+            }
+        }
+        Ok(artifacts)
+    }
+
+    fn engine_info(engine: &Self::Engine) -> Vec<(String, String)> {
+        engine.info()
+    }
+
     /// Start the instance
     /// The returned value should be a unique ID (such as a PID) for the instance.
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
@@ -81,23 +197,12 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         container.start()?;
 
         let exit_code = self.exit_code.clone();
+        let bundle = self.bundle.clone();
         thread::spawn(move || {
             // move the exit code guard into this thread
             let _guard = guard;
 
-            let status = match waitid(WaitID::Pid(Pid::from_raw(pid)), WaitPidFlag::WEXITED) {
-                Ok(WaitStatus::Exited(_, status)) => status,
-                Ok(WaitStatus::Signaled(_, sig, _)) => sig as i32,
-                Ok(_) => 0,
-                Err(Errno::ECHILD) => {
-                    log::info!("no child process");
-                    0
-                }
-                Err(e) => {
-                    log::error!("waitpid failed: {e}");
-                    137
-                }
-            } as u32;
+            let status = wait_for_exit(pid, &bundle);
             let _ = exit_code.set((status, Utc::now()));
         });
 
@@ -150,3 +255,92 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         self.exit_code.wait_timeout(t).copied()
     }
 }
+
+// Reads the wasm layers and platform of the container image from containerd.
+// Layers that don't match their digest or signature are never run, but any other error falls back to the container rootfs.
+fn load_modules<E: Engine>(
+    id: &str,
+    cfg: &InstanceConfig<E>,
+) -> Result<(Vec<WasmLayer>, Platform), SandboxError> {
+    // the layers of the tests run without containerd
+    #[cfg(any(test, feature = "testing"))]
+    if let Some((layers, platform)) = cfg.get_wasm_layers() {
+        return Ok((layers.to_vec(), platform.clone()));
+    }
+
+    let mut client =
+        containerd::Client::connect(cfg.get_containerd_address().as_str(), cfg.get_namespace())?;
+    if let Some(dir) = cfg.get_layers_dir() {
+        client = client.with_layers_dir(dir);
+    }
+    match client.load_modules(id, &cfg.get_engine()) {
+        Ok(modules) => Ok(modules),
+        Err(e @ SandboxError::FailedPrecondition(_)) => Err(e),
+        Err(e) => {
+            log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
+            Ok((vec![], Platform::default()))
+        }
+    }
+}
+
+// Waits for the child process `pid` to exit and returns its exit status.
+// The init process of a container created by a previous shim process is not a child of the shim,
+// see `poll_for_exit`.
+fn wait_for_exit(pid: i32, bundle: &Path) -> u32 {
+    (match waitid(WaitID::Pid(Pid::from_raw(pid)), WaitPidFlag::WEXITED) {
+        Ok(WaitStatus::Exited(_, status)) => status,
+        Ok(WaitStatus::Signaled(_, sig, _)) => sig as i32,
+        Ok(_) => 0,
+        Err(Errno::ECHILD) => {
+            log::info!("no child process, polling for the exit of {pid}");
+            return poll_for_exit(pid, bundle);
+        }
+        Err(e) => {
+            log::error!("waitpid failed: {e}");
+            137
+        }
+    }) as u32
+}
+
+// Waits for the process `pid` of a reattached container to exit.
+// After a shim restart the process is no longer a child of the shim, so we can only poll
+// until it goes away, and read the exit status it persisted in the bundle.
+fn poll_for_exit(pid: i32, bundle: &Path) -> u32 {
+    let pid = Pid::from_raw(pid);
+    while kill(pid, None).is_ok() {
+        thread::sleep(REATTACH_POLL_INTERVAL);
+    }
+    read_exit_status(bundle)
+}
+
+// Reads the exit status the init process of a container persisted in its bundle.
+fn read_exit_status(bundle: &Path) -> u32 {
+    let path = bundle.join(EXIT_STATUS_FILE);
+    match fs::read_to_string(&path).map(|status| status.trim().parse()) {
+        Ok(Ok(status)) => status,
+        _ => {
+            log::warn!("the exit status of the container is unknown, it's missing from {path:?}");
+            UNKNOWN_EXIT_STATUS
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_exit_status() -> anyhow::Result<()> {
+        let bundle = tempfile::tempdir()?;
+        assert_eq!(read_exit_status(bundle.path()), UNKNOWN_EXIT_STATUS);
+
+        // the file is created empty with the container, and written when its init process exits
+        fs::write(bundle.path().join(EXIT_STATUS_FILE), "")?;
+        assert_eq!(read_exit_status(bundle.path()), UNKNOWN_EXIT_STATUS);
+
+        fs::write(bundle.path().join(EXIT_STATUS_FILE), "42")?;
+        assert_eq!(read_exit_status(bundle.path()), 42);
+
+        Ok(())
+    }
+}
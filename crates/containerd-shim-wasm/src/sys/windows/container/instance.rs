@@ -15,6 +15,10 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         todo!();
     }
 
+    fn engine_info(engine: &Self::Engine) -> Vec<(String, String)> {
+        engine.info()
+    }
+
     /// Start the instance
     /// The returned value should be a unique ID (such as a PID) for the instance.
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
@@ -8,19 +8,25 @@ use std::time::Duration;
 
 use anyhow::{bail, Result};
 pub use containerd_shim_wasm_test_modules as modules;
+use oci_spec::image::{DescriptorBuilder, MediaType, Platform};
 use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
 
-use crate::sandbox::{Instance, InstanceConfig};
+#[cfg(unix)]
+pub use crate::sandbox::containerd::FakeContainerd;
+use crate::sandbox::{Instance, InstanceConfig, WasmLayer};
 use crate::sys::signals::SIGKILL;
 
 pub const TEST_NAMESPACE: &str = "runwasi-test";
+const CONTAINERD_ADDRESS: &str = "/run/containerd/containerd.sock";
 
 pub struct WasiTestBuilder<WasiInstance: Instance>
 where
     WasiInstance::Engine: Default + Send + Sync + Clone,
 {
     container_name: String,
+    containerd_address: String,
     tempdir: tempfile::TempDir,
+    wasm_layers: Option<(Vec<WasmLayer>, Platform)>,
     _phantom: PhantomData<WasiInstance>,
 }
 
@@ -29,6 +35,8 @@ where
     WasiInstance::Engine: Default + Send + Sync + Clone,
 {
     instance: WasiInstance,
+    container_name: String,
+    cfg: InstanceConfig<WasiInstance::Engine>,
     tempdir: tempfile::TempDir,
 }
 
@@ -59,7 +67,9 @@ where
 
         let builder = Self {
             container_name: "test".to_string(),
+            containerd_address: CONTAINERD_ADDRESS.to_string(),
             tempdir,
+            wasm_layers: None,
             _phantom: Default::default(),
         }
         .with_wasm([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?
@@ -118,39 +128,62 @@ where
         Ok(self)
     }
 
+    /// Creates the container from an OCI image with the wasm module of the test as its layer.
+    /// The image is imported in the [shared fake containerd](oci_helpers::containerd) unless it already
+    /// exists, and the image and the container are removed when the returned cleanup is dropped.
+    #[cfg(unix)]
     pub fn as_oci_image(
         mut self,
         image_name: Option<String>,
         container_name: Option<String>,
     ) -> Result<(Self, oci_helpers::OCICleanup)> {
         let image_name = image_name.unwrap_or("localhost/hello:latest".to_string());
+        let containerd = oci_helpers::containerd()?;
 
-        if !oci_helpers::image_exists(&image_name) {
+        if !containerd.has_image(&image_name) {
             let wasm_path = self.tempdir.path().join("rootfs").join("hello.wasm");
             let bytes = read(&wasm_path)?;
             let wasm_content = oci_helpers::ImageContent {
                 bytes,
                 media_type: oci_tar_builder::WASM_LAYER_MEDIA_TYPE.to_string(),
             };
-            oci_helpers::import_image(&image_name, &[&wasm_content])?;
+            containerd.import_image(&image_name, &[&wasm_content])?;
 
             // remove the file from the rootfs so it doesn't get treated like a regular container
             fs::remove_file(&wasm_path)?;
         }
 
         let container_name = container_name.unwrap_or("test".to_string());
-        oci_helpers::create_container(&container_name, &image_name)?;
+        containerd.create_container(&container_name, &image_name);
 
         self.container_name = container_name.clone();
+        self.containerd_address = containerd.address().to_string();
         Ok((
             self,
-            oci_helpers::OCICleanup {
-                image_name,
-                container_name,
-            },
+            oci_helpers::OCICleanup::in_fake_containerd(image_name, container_name, containerd),
         ))
     }
 
+    /// Runs the test with in-memory wasm layers, as if the container was created from an OCI image with
+    /// these layers and platform, without containerd. The layers are passed to the engine as they are.
+    pub fn with_wasm_layers(
+        mut self,
+        layers: impl IntoIterator<Item = WasmLayer>,
+        platform: Platform,
+    ) -> Result<Self> {
+        let layers: Vec<_> = layers.into_iter().collect();
+
+        log::info!(
+            "setting wasi test wasm layers to {} layers for {} {}",
+            layers.len(),
+            platform.os(),
+            platform.architecture()
+        );
+
+        self.wasm_layers = Some((layers, platform));
+        Ok(self)
+    }
+
     pub fn build(self) -> Result<WasiTest<WasiInstance>> {
         let tempdir = self.tempdir;
         let dir = tempdir.path();
@@ -160,18 +193,41 @@ where
         let mut cfg = InstanceConfig::new(
             WasiInstance::Engine::default(),
             TEST_NAMESPACE,
-            "/run/containerd/containerd.sock",
+            self.containerd_address,
         );
         cfg.set_bundle(dir)
             .set_stdout(dir.join("stdout"))
             .set_stderr(dir.join("stderr"))
             .set_stdin(dir.join("stdin"));
+        if let Some((layers, platform)) = self.wasm_layers {
+            cfg.set_wasm_layers(layers, platform);
+        }
 
-        let instance = WasiInstance::new(self.container_name, Some(&cfg))?;
-        Ok(WasiTest { instance, tempdir })
+        let instance = WasiInstance::new(self.container_name.clone(), Some(&cfg))?;
+        Ok(WasiTest {
+            instance,
+            container_name: self.container_name,
+            cfg,
+            tempdir,
+        })
     }
 }
 
+/// Creates a wasm layer with the given media type and content, with a descriptor
+/// like the one of a layer read from the containerd content store.
+pub fn wasm_layer(media_type: impl AsRef<str>, bytes: impl AsRef<[u8]>) -> Result<WasmLayer> {
+    let bytes = bytes.as_ref().to_vec();
+    let config = DescriptorBuilder::default()
+        .media_type(MediaType::from(media_type.as_ref()))
+        .digest(format!("sha256:{}", sha256::digest(bytes.as_slice())))
+        .size(bytes.len() as i64)
+        .build()?;
+    Ok(WasmLayer {
+        config,
+        layer: bytes.into(),
+    })
+}
+
 impl<WasiInstance: Instance> WasiTest<WasiInstance>
 where
     WasiInstance::Engine: Default + Send + Sync + Clone,
@@ -190,6 +246,15 @@ where
         Ok(self)
     }
 
+    /// Reattaches to the container of the test, like a shim process that restarted.
+    pub fn reattach(&self) -> Result<WasiInstance> {
+        log::info!("reattaching wasi test");
+        Ok(WasiInstance::reattach(
+            self.container_name.clone(),
+            Some(&self.cfg),
+        )?)
+    }
+
     pub fn delete(&self) -> Result<&Self> {
         log::info!("deleting wasi test");
         self.instance.delete()?;
@@ -222,27 +287,85 @@ where
 pub mod oci_helpers {
     use std::fs::{write, File};
     use std::process::{Command, Stdio};
+    #[cfg(unix)]
+    use std::sync::{Arc, Mutex, Weak};
     use std::time::{Duration, Instant};
 
+    #[cfg(unix)]
+    use anyhow::Context;
     use anyhow::{bail, Result};
     use oci_spec::image::{self as spec, Arch};
     use oci_tar_builder::Builder;
 
+    #[cfg(unix)]
+    use super::FakeContainerd;
     use super::TEST_NAMESPACE;
 
+    /// Removes a test image and its container when dropped,
+    /// from the fake containerd they were created in, or with `ctr`.
     pub struct OCICleanup {
         pub image_name: String,
         pub container_name: String,
+        #[cfg(unix)]
+        containerd: Option<Arc<FakeContainerd>>,
+    }
+
+    impl OCICleanup {
+        /// Cleans up an image and container that were created with `ctr`.
+        pub fn new(image_name: String, container_name: String) -> Self {
+            Self {
+                image_name,
+                container_name,
+                #[cfg(unix)]
+                containerd: None,
+            }
+        }
+
+        /// Cleans up an image and container of a fake containerd, which is kept running until then.
+        #[cfg(unix)]
+        pub fn in_fake_containerd(
+            image_name: String,
+            container_name: String,
+            containerd: Arc<FakeContainerd>,
+        ) -> Self {
+            Self {
+                image_name,
+                container_name,
+                containerd: Some(containerd),
+            }
+        }
     }
 
     impl Drop for OCICleanup {
         fn drop(&mut self) {
             log::debug!("dropping OCIGuard");
+            #[cfg(unix)]
+            if let Some(containerd) = &self.containerd {
+                containerd.remove_container(&self.container_name);
+                containerd.remove_image(&self.image_name);
+                return;
+            }
             clean_container(self.container_name.clone()).unwrap();
             clean_image(self.image_name.clone()).unwrap();
         }
     }
 
+    /// The fake containerd that [`as_oci_image`](super::WasiTestBuilder::as_oci_image) imports the images in.
+    /// Like a containerd daemon, it's shared by all the images that exist at the same time,
+    /// and it's stopped once they are all cleaned up.
+    #[cfg(unix)]
+    pub fn containerd() -> Result<Arc<FakeContainerd>> {
+        static CONTAINERD: Mutex<Weak<FakeContainerd>> = Mutex::new(Weak::new());
+
+        let mut shared = CONTAINERD.lock().unwrap();
+        if let Some(containerd) = shared.upgrade() {
+            return Ok(containerd);
+        }
+        let containerd = Arc::new(FakeContainerd::start()?);
+        *shared = Arc::downgrade(&containerd);
+        Ok(containerd)
+    }
+
     pub fn clean_container(container_name: String) -> Result<()> {
         log::debug!("deleting container '{}'", container_name);
         let success = Command::new("ctr")
@@ -459,52 +582,264 @@ pub mod oci_helpers {
         stdout.contains(image_name)
     }
 
+    /// Returns a `runwasi.io/precompiled/<engine>/<key>` label of a layer of the
+    /// [shared fake containerd](containerd), and its value, the digest of the precompiled content.
+    #[cfg(unix)]
     pub fn get_content_label() -> Result<(String, String)> {
-        let mut grep = Command::new("grep")
-            .arg("-ohE")
-            .arg("runwasi.io/precompiled/[[:alpha:]]*/[0-9]+=.*")
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()?;
+        let labels = containerd()?.content_labels();
+        log::debug!("content labels: {labels:?}");
+
+        labels
+            .into_iter()
+            .find(|(label, value)| {
+                let is_precompiled = label
+                    .strip_prefix("runwasi.io/precompiled/")
+                    .and_then(|label| label.split_once('/'))
+                    .is_some_and(|(_engine, key)| key.bytes().all(|b| b.is_ascii_digit()));
+                is_precompiled && value.starts_with("sha256:")
+            })
+            .context("no precompiled content")
+    }
 
-        Command::new("ctr")
-            .arg("-n")
-            .arg(TEST_NAMESPACE)
-            .arg("content")
-            .arg("ls")
-            .stdout(grep.stdin.take().unwrap())
-            .spawn()?;
+    /// Removes content from the [shared fake containerd](containerd).
+    #[cfg(unix)]
+    pub fn remove_content(digest: String) -> Result<()> {
+        log::debug!("cleaning content '{}'", digest);
+        containerd()?.remove_content(&digest);
+        Ok(())
+    }
+}
 
-        let output = grep.wait_with_output()?;
+/// Conformance suite of the scenarios every engine is expected to pass.
+///
+/// Each scenario is a generic function that takes the [`Instance`] type of the engine, e.g.
+/// `conformance::hello_world::<MyInstance>()`, and [`conformance_tests!`](crate::conformance_tests)
+/// generates a test for each of them.
+pub mod conformance {
+    use std::time::Duration;
 
-        let stdout = String::from_utf8(output.stdout)?;
+    use anyhow::ensure;
+    pub use anyhow::Result;
+    use oci_spec::image::{Arch, PlatformBuilder};
+    use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
 
-        log::debug!("stdout: {}", stdout);
+    use super::modules::*;
+    use super::{wasm_layer, Instance, WasiTest};
 
-        let label: Vec<&str> = stdout.split('=').collect();
+    const TIMEOUT: Duration = Duration::from_secs(10);
 
-        Ok((
-            label.first().unwrap().trim().to_string(),
-            label.last().unwrap().trim().to_string(),
-        ))
+    type Scenario = fn() -> Result<()>;
+
+    /// A container that was never started can be deleted.
+    pub fn delete_after_create<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        WasiTest::<I>::builder()?.build()?.delete()?;
+        Ok(())
     }
 
-    pub fn remove_content(digest: String) -> Result<()> {
-        log::debug!("cleaning content '{}'", digest);
-        let success = Command::new("ctr")
-            .arg("-n")
-            .arg(TEST_NAMESPACE)
-            .arg("content")
-            .arg("rm")
-            .arg(digest)
-            .spawn()?
-            .wait()?
-            .success();
+    /// A module prints to stdout and exits.
+    pub fn hello_world<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, stdout, _) = WasiTest::<I>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        ensure!(stdout == "hello world\n", "stdout was {stdout:?}");
+        Ok(())
+    }
 
-        if !success {
-            bail!("failed to remove content");
-        }
+    /// A module is loaded from the wasm layers of an OCI image, imported in a fake containerd.
+    #[cfg(unix)]
+    pub fn hello_world_oci<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (builder, _oci_cleanup) = WasiTest::<I>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .as_oci_image(None, None)?;
+
+        let (exit_code, stdout, _) = builder.build()?.start()?.wait(TIMEOUT)?;
 
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        ensure!(stdout == "hello world\n", "stdout was {stdout:?}");
         Ok(())
     }
+
+    /// A module is loaded from in-memory wasm layers, like the layers of an OCI image, without containerd.
+    pub fn hello_world_oci_layers<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let layer = wasm_layer(WASM_LAYER_MEDIA_TYPE, HELLO_WORLD)?;
+        let platform = PlatformBuilder::default()
+            .os("wasip1")
+            .architecture(Arch::Wasm)
+            .build()?;
+
+        let (exit_code, stdout, _) = WasiTest::<I>::builder()?
+            .with_wasm_layers([layer], platform)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        ensure!(stdout == "hello world\n", "stdout was {stdout:?}");
+        Ok(())
+    }
+
+    /// The entrypoint `#func` selects the exported function to run.
+    pub fn custom_entrypoint<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, stdout, _) = WasiTest::<I>::builder()?
+            .with_start_fn("foo")?
+            .with_wasm(CUSTOM_ENTRYPOINT)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        ensure!(stdout == "hello world\n", "stdout was {stdout:?}");
+        Ok(())
+    }
+
+    /// A trap is a non-zero exit code.
+    pub fn unreachable<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, _, _) = WasiTest::<I>::builder()?
+            .with_wasm(UNREACHABLE)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code != 0, "exit code was 0");
+        Ok(())
+    }
+
+    /// The exit code of `proc_exit` is the exit code of the container.
+    pub fn exit_code<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, _, _) = WasiTest::<I>::builder()?
+            .with_wasm(EXIT_CODE)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 42, "exit code was {exit_code}");
+        Ok(())
+    }
+
+    /// The module runs with the seccomp profile of the container, in its root directory.
+    pub fn seccomp<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, stdout, _) = WasiTest::<I>::builder()?
+            .with_wasm(SECCOMP)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        ensure!(
+            stdout.trim() == "current working dir: /",
+            "stdout was {stdout:?}"
+        );
+        Ok(())
+    }
+
+    /// The default devices of a container, like `/dev/null`, are available.
+    pub fn has_default_devices<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let (exit_code, _, _) = WasiTest::<I>::builder()?
+            .with_wasm(HAS_DEFAULT_DEVICES)?
+            .build()?
+            .start()?
+            .wait(TIMEOUT)?;
+
+        ensure!(exit_code == 0, "exit code was {exit_code}");
+        Ok(())
+    }
+
+    /// Runs every scenario of the suite, stopping at the first failure.
+    pub fn run_all<I: Instance>() -> Result<()>
+    where
+        I::Engine: Default,
+    {
+        let scenarios: &[(&str, Scenario)] = &[
+            ("delete_after_create", delete_after_create::<I>),
+            ("hello_world", hello_world::<I>),
+            #[cfg(unix)]
+            ("hello_world_oci", hello_world_oci::<I>),
+            ("hello_world_oci_layers", hello_world_oci_layers::<I>),
+            ("custom_entrypoint", custom_entrypoint::<I>),
+            ("unreachable", unreachable::<I>),
+            ("exit_code", exit_code::<I>),
+            ("seccomp", seccomp::<I>),
+            ("has_default_devices", has_default_devices::<I>),
+        ];
+        for &(name, scenario) in scenarios {
+            log::info!("running conformance scenario {name}");
+            scenario().map_err(|err| err.context(format!("conformance scenario {name}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Generates a `conformance` module with a test for each scenario of the
+/// [conformance suite](crate::testing::conformance) for an [`Instance`] type, e.g.:
+///
+/// ```ignore
+/// containerd_shim_wasm::conformance_tests!(MyInstance);
+/// ```
+///
+/// The scenarios can be restricted to a subset, e.g. `conformance_tests!(MyInstance; hello_world, exit_code)`,
+/// and attributes can be added to their tests, e.g. `#[cfg(unix)] hello_world_oci` for the scenarios that are unix only.
+/// The tests are run one at a time with `serial_test`, which must be a dev-dependency of the crate.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($instance:ty) => {
+        $crate::conformance_tests!(
+            $instance;
+            delete_after_create,
+            hello_world,
+            #[cfg(unix)]
+            hello_world_oci,
+            hello_world_oci_layers,
+            custom_entrypoint,
+            unreachable,
+            exit_code,
+            seccomp,
+            has_default_devices
+        );
+    };
+    ($instance:ty; $($(#[$attr:meta])* $scenario:ident),* $(,)?) => {
+        mod conformance {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $(
+                #[test]
+                #[::serial_test::serial]
+                $(#[$attr])*
+                fn $scenario() -> $crate::testing::conformance::Result<()> {
+                    $crate::testing::conformance::$scenario::<$instance>()
+                }
+            )*
+        }
+    };
 }
@@ -1,26 +1,111 @@
+#[cfg(feature = "aot")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "aot")]
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
 use anyhow::{Context, Result};
 use containerd_shim_wasm::container::{Engine, Entrypoint, Instance, RuntimeContext, Stdio};
+#[cfg(feature = "aot")]
+use containerd_shim_wasm::sandbox::WasmLayer;
+#[cfg(feature = "aot")]
+use wasmedge_sdk::config::CompilerConfigOptions;
 use wasmedge_sdk::config::{ConfigBuilder, HostRegistrationConfigOptions};
 use wasmedge_sdk::plugin::PluginManager;
+use wasmedge_sdk::utils::CoreVersion;
 use wasmedge_sdk::VmBuilder;
+#[cfg(feature = "aot")]
+use wasmedge_sdk::{Compiler, CompilerOutputFormat};
 
 pub type WasmEdgeInstance = Instance<WasmEdgeEngine>;
 
+/// Environment variable with the directory, or file, to load the WasmEdge plugins from,
+/// instead of the default plugin paths of WasmEdge.
+pub const PLUGIN_DIR_ENV: &str = "RUNWASI_WASMEDGE_PLUGIN_DIR";
+
+/// Environment variable with the comma separated names of the plugins to register in the VM,
+/// instead of all the loaded plugins.
+pub const PLUGINS_ENV: &str = "RUNWASI_WASMEDGE_PLUGINS";
+
+/// The plugins of a [`WasmEdgeEngine`].
+#[derive(Clone, Debug, Default)]
+pub struct PluginConfig {
+    /// The directory, or file, to load the plugins from.
+    /// When `None`, the plugins are loaded from the default plugin paths of WasmEdge.
+    pub dir: Option<PathBuf>,
+    /// The names of the plugins to register in the VM.
+    /// When `None`, all the loaded plugins are registered.
+    pub allowed: Option<Vec<String>>,
+}
+
+impl PluginConfig {
+    /// The plugin configuration from the [`PLUGIN_DIR_ENV`] and [`PLUGINS_ENV`] environment variables.
+    pub fn from_env() -> Self {
+        let dir = std::env::var_os(PLUGIN_DIR_ENV).map(PathBuf::from);
+        let allowed = std::env::var(PLUGINS_ENV).ok().map(|plugins| {
+            plugins
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        Self { dir, allowed }
+    }
+}
+
 #[derive(Clone)]
 pub struct WasmEdgeEngine {
     vm: wasmedge_sdk::Vm,
+    plugins: Vec<String>,
 }
 
-impl Default for WasmEdgeEngine {
-    fn default() -> Self {
+impl WasmEdgeEngine {
+    /// Creates an engine with a VM that has the allowed plugins registered.
+    /// The plugins are loaded once here, and every container reuses the VM.
+    pub fn new(plugin_config: &PluginConfig) -> Result<Self> {
+        PluginManager::load(plugin_config.dir.as_deref())?;
+
+        let mut plugins = PluginManager::names();
+        if let Some(allowed) = &plugin_config.allowed {
+            for name in allowed.iter().filter(|name| !plugins.contains(name)) {
+                log::warn!("wasmedge plugin {name:?} is allowed but wasn't loaded");
+            }
+            plugins.retain(|name| allowed.contains(name));
+        }
+        log::info!("wasmedge plugins: {plugins:?}");
+
         let host_options = HostRegistrationConfigOptions::default();
         let host_options = host_options.wasi(true);
         let config = ConfigBuilder::default()
             .with_host_registration_config(host_options)
-            .build()
-            .unwrap();
-        let vm = VmBuilder::new().with_config(config).build().unwrap();
-        Self { vm }
+            .build()?;
+        let vm = plugins
+            .iter()
+            .fold(VmBuilder::new().with_config(config), |builder, name| {
+                builder.with_plugin(name, None)
+            })
+            .build()?;
+
+        Ok(Self { vm, plugins })
+    }
+
+    /// The names of the plugins registered in the VM.
+    pub fn plugins(&self) -> &[String] {
+        &self.plugins
+    }
+}
+
+impl Default for WasmEdgeEngine {
+    fn default() -> Self {
+        Self::new(&PluginConfig::from_env()).unwrap_or_else(|err| {
+            log::warn!("{err:#}, running without plugins");
+            let no_plugins = PluginConfig {
+                dir: None,
+                allowed: Some(vec![]),
+            };
+            Self::new(&no_plugins).expect("failed to create the wasmedge VM")
+        })
     }
 }
 
@@ -50,9 +135,6 @@ impl Engine for WasmEdgeEngine {
 
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
-        PluginManager::load(None)?;
-        let vm = vm.auto_detect_plugins()?;
-
         let wasm_bytes = source.as_bytes()?;
         let vm = vm
             .register_module_from_bytes(&mod_name, wasm_bytes)
@@ -70,4 +152,120 @@ impl Engine for WasmEdgeEngine {
 
         Ok(status as i32)
     }
+
+    fn info(&self) -> Vec<(String, String)> {
+        vec![
+            ("WasmEdge".to_string(), CoreVersion::version_string()),
+            ("Plugins".to_string(), self.plugins.join(", ")),
+        ]
+    }
+
+    /// Compile the layers to the universal wasm format: wasm binaries with the native code
+    /// of the AOT compiler in a custom section, that run the native code when it's loaded.
+    #[cfg(feature = "aot")]
+    fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
+        let compiler_options = CompilerConfigOptions::new().out_format(CompilerOutputFormat::Wasm);
+        let config = ConfigBuilder::default()
+            .with_compiler_config(compiler_options)
+            .build()?;
+        let compiler = Compiler::new(Some(&config))?;
+        let out_dir = tempfile::tempdir()?;
+
+        let mut compiled_layers = Vec::<Option<Vec<u8>>>::with_capacity(layers.len());
+        for (i, layer) in layers.iter().enumerate() {
+            if is_aot_compiled(&layer.layer) {
+                log::info!("Already precompiled");
+                compiled_layers.push(None);
+                continue;
+            }
+
+            let path = compiler.compile_from_bytes(
+                &layer.layer[..],
+                format!("layer-{i}"),
+                out_dir.path(),
+            )?;
+            compiled_layers.push(Some(std::fs::read(path)?));
+        }
+
+        Ok(compiled_layers)
+    }
+
+    /// The native code of the AOT compiler depends on the version of WasmEdge,
+    /// and on the CPU it's compiled for.
+    #[cfg(feature = "aot")]
+    fn can_precompile(&self) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        CoreVersion::version_string().hash(&mut hasher);
+        std::env::consts::ARCH.hash(&mut hasher);
+        cpu_features().hash(&mut hasher);
+        Some(hasher.finish().to_string())
+    }
+}
+
+/// Whether a wasm binary has the `wasmedge` custom section with the native code of the AOT compiler.
+#[cfg(feature = "aot")]
+pub(crate) fn is_aot_compiled(wasm: &[u8]) -> bool {
+    let Some(mut sections) = wasm.strip_prefix(b"\0asm\x01\0\0\0") else {
+        return false;
+    };
+    while let Some((&id, rest)) = sections.split_first() {
+        let Some((size, rest)) = read_leb128(rest) else {
+            return false;
+        };
+        let (Some(content), Some(rest)) = (rest.get(..size), rest.get(size..)) else {
+            return false;
+        };
+        if id == 0 {
+            let name = read_leb128(content).and_then(|(len, name)| name.get(..len));
+            if name == Some(&b"wasmedge"[..]) {
+                return true;
+            }
+        }
+        sections = rest;
+    }
+    false
+}
+
+/// Read an unsigned LEB128 integer of at most 32 bits.
+#[cfg(feature = "aot")]
+fn read_leb128(bytes: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+/// The features of the CPU that the native code of the AOT compiler can use.
+#[cfg(feature = "aot")]
+fn cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = vec![];
+
+    #[cfg(target_arch = "x86_64")]
+    macro_rules! detect {
+        ($($feature:tt),*) => {
+            $(if std::arch::is_x86_feature_detected!($feature) {
+                features.push($feature);
+            })*
+        };
+    }
+    #[cfg(target_arch = "aarch64")]
+    macro_rules! detect {
+        ($($feature:tt),*) => {
+            $(if std::arch::is_aarch64_feature_detected!($feature) {
+                features.push($feature);
+            })*
+        };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    detect!("sse4.1", "sse4.2", "popcnt", "avx", "avx2", "bmi1", "bmi2", "fma", "lzcnt", "avx512f");
+    #[cfg(target_arch = "aarch64")]
+    detect!("neon", "lse", "sve");
+
+    features
 }
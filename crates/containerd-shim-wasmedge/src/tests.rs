@@ -1,58 +1,91 @@
+use std::path::PathBuf;
 use std::time::Duration;
 
+use containerd_shim_wasm::container::Engine;
 //use containerd_shim_wasm::sandbox::Instance;
 use containerd_shim_wasm::testing::modules::*;
+#[cfg(feature = "aot")]
+use containerd_shim_wasm::testing::oci_helpers;
 use containerd_shim_wasm::testing::WasiTest;
 use serial_test::serial;
 
-use crate::instance::WasmEdgeInstance as WasiInstance;
+#[cfg(feature = "aot")]
+use crate::instance::is_aot_compiled;
+use crate::instance::{
+    PluginConfig, WasmEdgeEngine, WasmEdgeInstance as WasiInstance, PLUGINS_ENV, PLUGIN_DIR_ENV,
+};
 
-#[test]
-#[serial]
-fn test_delete_after_create() -> anyhow::Result<()> {
-    WasiTest::<WasiInstance>::builder()?.build()?.delete()?;
-    Ok(())
+containerd_shim_wasm::conformance_tests!(WasiInstance);
+
+// Get the path to binary where the `WasmEdge_VersionGet` C ffi symbol is defined.
+// If wasmedge is dynamically linked, this will be the path to the `.so`.
+// If wasmedge is statically linked, this will be the path to the current executable.
+fn get_wasmedge_binary_path() -> std::path::PathBuf {
+    use std::os::unix::prelude::OsStrExt;
+
+    extern "C" {
+        pub fn WasmEdge_VersionGet() -> *const libc::c_char;
+    }
+
+    let mut info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(WasmEdge_VersionGet as *const libc::c_void, &mut info) } == 0 {
+        // no dladdr support, must be a static binary
+        std::env::current_exe().unwrap_or_default()
+    } else {
+        let fname = unsafe { std::ffi::CStr::from_ptr(info.dli_fname) };
+        let fname = std::ffi::OsStr::from_bytes(fname.to_bytes());
+        std::path::PathBuf::from(fname)
+    }
 }
 
+#[cfg(feature = "static")]
 #[test]
-#[serial]
-fn test_hello_world() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HELLO_WORLD)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
-
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "hello world\n");
+fn check_static_linking() {
+    let wasmedge_path = get_wasmedge_binary_path().canonicalize().unwrap();
+    let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
+    assert!(wasmedge_path == current_exe);
+}
 
-    Ok(())
+#[cfg(not(feature = "static"))]
+#[test]
+fn check_dynamic_linking() {
+    let wasmedge_path = get_wasmedge_binary_path().canonicalize().unwrap();
+    let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
+    assert!(wasmedge_path != current_exe);
 }
 
 #[test]
 #[serial]
-fn test_hello_world_oci() -> anyhow::Result<()> {
-    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+#[cfg(feature = "aot")]
+fn test_hello_world_oci_uses_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
         .with_wasm(HELLO_WORLD)?
-        .as_oci_image(None, None)?;
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c1".to_string()),
+        )?;
 
     let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
     assert_eq!(stdout, "hello world\n");
 
-    Ok(())
-}
+    let (label, _id) = oci_helpers::get_content_label()?;
+    assert!(
+        label.starts_with("runwasi.io/precompiled/wasmedge/"),
+        "was {}",
+        label
+    );
 
-#[test]
-#[serial]
-fn test_custom_entrypoint() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_start_fn("foo")?
-        .with_wasm(CUSTOM_ENTRYPOINT)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+    // run second time, it should succeed without recompiling
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c2".to_string()),
+        )?;
+
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
     assert_eq!(stdout, "hello world\n");
@@ -61,95 +94,56 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
 }
 
 #[test]
-#[serial]
-fn test_unreachable() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(UNREACHABLE)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+#[cfg(feature = "aot")]
+fn test_is_aot_compiled() {
+    // a module with an empty function type section
+    let module = b"\0asm\x01\0\0\0\x01\x01\x00".to_vec();
+    assert!(!is_aot_compiled(&module));
 
-    assert_ne!(exit_code, 0);
+    let mut name_section = module.clone();
+    name_section.extend_from_slice(b"\0\x05\x04name");
+    assert!(!is_aot_compiled(&name_section));
 
-    Ok(())
-}
-
-#[test]
-#[serial]
-fn test_exit_code() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(EXIT_CODE)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+    let mut aot_section = module.clone();
+    aot_section.extend_from_slice(b"\0\x0b\x08wasmedge\x01\x02");
+    assert!(is_aot_compiled(&aot_section));
 
-    assert_eq!(exit_code, 42);
-
-    Ok(())
+    assert!(!is_aot_compiled(b"not wasm"));
 }
 
 #[test]
 #[serial]
-fn test_seccomp() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SECCOMP)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
-
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout.trim(), "current working dir: /");
-
-    Ok(())
+fn test_plugin_config_from_env() {
+    std::env::set_var(PLUGIN_DIR_ENV, "/opt/wasmedge/plugin");
+    std::env::set_var(PLUGINS_ENV, "wasi_nn, wasi_crypto,");
+    let config = PluginConfig::from_env();
+    std::env::remove_var(PLUGIN_DIR_ENV);
+    std::env::remove_var(PLUGINS_ENV);
+
+    assert_eq!(config.dir, Some(PathBuf::from("/opt/wasmedge/plugin")));
+    assert_eq!(
+        config.allowed,
+        Some(vec!["wasi_nn".to_string(), "wasi_crypto".to_string()])
+    );
+
+    let config = PluginConfig::from_env();
+    assert_eq!(config.dir, None);
+    assert_eq!(config.allowed, None);
 }
 
 #[test]
 #[serial]
-fn test_has_default_devices() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HAS_DEFAULT_DEVICES)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
-
-    assert_eq!(exit_code, 0);
+fn test_plugins_allow_list() -> anyhow::Result<()> {
+    let config = PluginConfig {
+        dir: None,
+        allowed: Some(vec!["not_a_plugin".to_string()]),
+    };
+    let engine = WasmEdgeEngine::new(&config)?;
+    assert!(engine.plugins().is_empty());
+
+    let info = engine.info();
+    assert!(info.iter().any(|(name, _)| name == "WasmEdge"));
+    assert!(info.contains(&("Plugins".to_string(), String::new())));
 
     Ok(())
 }
-
-// Get the path to binary where the `WasmEdge_VersionGet` C ffi symbol is defined.
-// If wasmedge is dynamically linked, this will be the path to the `.so`.
-// If wasmedge is statically linked, this will be the path to the current executable.
-fn get_wasmedge_binary_path() -> std::path::PathBuf {
-    use std::os::unix::prelude::OsStrExt;
-
-    extern "C" {
-        pub fn WasmEdge_VersionGet() -> *const libc::c_char;
-    }
-
-    let mut info = unsafe { std::mem::zeroed() };
-    if unsafe { libc::dladdr(WasmEdge_VersionGet as *const libc::c_void, &mut info) } == 0 {
-        // no dladdr support, must be a static binary
-        std::env::current_exe().unwrap_or_default()
-    } else {
-        let fname = unsafe { std::ffi::CStr::from_ptr(info.dli_fname) };
-        let fname = std::ffi::OsStr::from_bytes(fname.to_bytes());
-        std::path::PathBuf::from(fname)
-    }
-}
-
-#[cfg(feature = "static")]
-#[test]
-fn check_static_linking() {
-    let wasmedge_path = get_wasmedge_binary_path().canonicalize().unwrap();
-    let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
-    assert!(wasmedge_path == current_exe);
-}
-
-#[cfg(not(feature = "static"))]
-#[test]
-fn check_dynamic_linking() {
-    let wasmedge_path = get_wasmedge_binary_path().canonicalize().unwrap();
-    let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
-    assert!(wasmedge_path != current_exe);
-}
@@ -0,0 +1,93 @@
+//! The compilers the wasmer shim can use, enabled with the `cranelift`, `singlepass` and `llvm` cargo features.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+#[cfg(not(any(feature = "cranelift", feature = "singlepass", feature = "llvm")))]
+compile_error!("at least one of the `cranelift`, `singlepass` or `llvm` features must be enabled");
+
+/// Environment variable of the shim with the compiler used when a container doesn't select one.
+pub const COMPILER_ENV: &str = "RUNWASI_WASMER_COMPILER";
+
+/// Annotation of a container with the compiler to use for it.
+pub const COMPILER_ANNOTATION: &str = "wasmer.runwasi.io/compiler";
+
+/// A wasmer compiler: Cranelift compiles fast code quickly, Singlepass compiles the fastest
+/// for latency sensitive workloads, and LLVM compiles the fastest code for long running ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compiler {
+    Cranelift,
+    Singlepass,
+    Llvm,
+}
+
+impl Compiler {
+    /// The compiler used when neither the shim nor the container select one,
+    /// the first enabled of Cranelift, Singlepass and LLVM.
+    pub const fn build_default() -> Self {
+        if cfg!(feature = "cranelift") {
+            Compiler::Cranelift
+        } else if cfg!(feature = "singlepass") {
+            Compiler::Singlepass
+        } else {
+            Compiler::Llvm
+        }
+    }
+
+    /// The compiler set with the [`COMPILER_ENV`] environment variable of the shim,
+    /// or the [`build default`](Compiler::build_default).
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(COMPILER_ENV) {
+            Ok(compiler) => compiler.parse(),
+            Err(_) => Ok(Self::build_default()),
+        }
+    }
+
+    /// Whether the compiler is enabled in this build of the shim.
+    pub const fn is_enabled(self) -> bool {
+        match self {
+            Compiler::Cranelift => cfg!(feature = "cranelift"),
+            Compiler::Singlepass => cfg!(feature = "singlepass"),
+            Compiler::Llvm => cfg!(feature = "llvm"),
+        }
+    }
+
+    /// Create an engine that compiles modules with this compiler.
+    pub fn engine(self) -> Result<wasmer::Engine> {
+        match self {
+            #[cfg(feature = "cranelift")]
+            Compiler::Cranelift => Ok(wasmer::Cranelift::default().into()),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => Ok(wasmer::Singlepass::default().into()),
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => Ok(wasmer::LLVM::default().into()),
+            #[allow(unreachable_patterns)]
+            compiler => bail!("the {compiler} compiler is not enabled in this build of the shim"),
+        }
+    }
+}
+
+impl FromStr for Compiler {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cranelift" => Ok(Compiler::Cranelift),
+            "singlepass" => Ok(Compiler::Singlepass),
+            "llvm" => Ok(Compiler::Llvm),
+            _ => bail!("unknown wasmer compiler {s:?}, expected cranelift, singlepass or llvm"),
+        }
+    }
+}
+
+impl fmt::Display for Compiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compiler::Cranelift => write!(f, "cranelift"),
+            Compiler::Singlepass => write!(f, "singlepass"),
+            Compiler::Llvm => write!(f, "llvm"),
+        }
+    }
+}
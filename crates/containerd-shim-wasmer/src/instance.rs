@@ -1,14 +1,80 @@
-use anyhow::Result;
-use containerd_shim_wasm::container::{Engine, Entrypoint, Instance, RuntimeContext, Stdio};
-use wasmer::{Module, Store};
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use containerd_shim_wasm::container::{
+    Engine, Entrypoint, Instance, RuntimeContext, Source, Stdio, WasiVersion, WasmBinaryType,
+};
+use containerd_shim_wasm::sandbox::WasmLayer;
+use wasmer::{Artifact, Module, NativeEngineExt, Store};
+use wasmer_wasix::bin_factory::BinaryPackage;
+use wasmer_wasix::http::{default_http_client, HttpClient, HttpRequest, HttpResponse};
+use wasmer_wasix::runners::wasi::WasiRunner;
+use wasmer_wasix::runners::{MappedDirectory, Runner};
+use wasmer_wasix::runtime::package_loader::BuiltinPackageLoader;
+use wasmer_wasix::runtime::resolver::MultiSource;
+use wasmer_wasix::runtime::task_manager::tokio::TokioTaskManager;
 use wasmer_wasix::virtual_fs::host_fs::FileSystem;
-use wasmer_wasix::{WasiEnv, WasiError};
+use wasmer_wasix::{PluggableRuntime, WasiEnv, WasiError, WasiRuntimeError};
+use webc::Container;
+
+use crate::compiler::{Compiler, COMPILER_ANNOTATION};
 
 pub type WasmerInstance = Instance<WasmerEngine>;
 
-#[derive(Clone, Default)]
+/// Media type of the OCI layers with a [webc](https://docs.wasmer.io/registry/webc) package.
+pub const WEBC_LAYER_MEDIA_TYPE: &str = "application/webc";
+
+/// Annotation of a container that lets the shim load the dependencies of its webc package
+/// from the wasmer registry, when it's `true`.
+/// The dependencies are not part of the image, so their content isn't verified,
+/// and without the annotation the packages with dependencies are rejected.
+pub const WEBC_REGISTRY_ANNOTATION: &str = "wasmer.runwasi.io/webc-registry";
+
+#[derive(Clone)]
 pub struct WasmerEngine {
-    engine: wasmer::Cranelift,
+    engine: wasmer::Engine,
+    compiler: Compiler,
+}
+
+impl WasmerEngine {
+    pub fn new(compiler: Compiler) -> Result<Self> {
+        Ok(Self {
+            engine: compiler.engine()?,
+            compiler,
+        })
+    }
+
+    /// The compiler selected by the [`COMPILER_ANNOTATION`] of the container,
+    /// or the compiler of the shim.
+    fn compiler(&self, ctx: &impl RuntimeContext) -> Result<Compiler> {
+        let Some(compiler) = ctx.annotations().and_then(|a| a.get(COMPILER_ANNOTATION)) else {
+            return Ok(self.compiler);
+        };
+        let compiler: Compiler = compiler
+            .parse()
+            .with_context(|| format!("invalid {COMPILER_ANNOTATION} annotation"))?;
+        if !compiler.is_enabled() {
+            bail!("the {compiler} compiler is not enabled in this build of the shim");
+        }
+        Ok(compiler)
+    }
+}
+
+impl Default for WasmerEngine {
+    fn default() -> Self {
+        let compiler = Compiler::from_env().unwrap_or_else(|err| {
+            log::warn!("{err:#}, using {}", Compiler::build_default());
+            Compiler::build_default()
+        });
+        Self::new(compiler).unwrap_or_else(|err| {
+            log::warn!("{err:#}, using {}", Compiler::build_default());
+            Self::new(Compiler::build_default()).expect("the default compiler is enabled")
+        })
+    }
 }
 
 impl Engine for WasmerEngine {
@@ -28,11 +94,39 @@ impl Engine for WasmerEngine {
 
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
-        log::info!("Create a Store");
-        let mut store = Store::new(self.engine.clone());
+        let compiler = self.compiler(ctx)?;
+
+        if let Source::Oci(layers) = &source {
+            if let Some(webc) = layers.iter().find(|layer| is_webc_layer(layer)) {
+                log::info!("running webc package with {compiler}");
+                let engine = if compiler == self.compiler {
+                    self.engine.clone()
+                } else {
+                    compiler.engine()?
+                };
+                return run_webc(engine, webc, &func, args, uses_registry(ctx), stdio);
+            }
+        }
 
         let wasm_bytes = source.as_bytes()?;
-        let module = Module::from_binary(&store, &wasm_bytes)?;
+        let is_precompiled = Artifact::is_deserializable(&wasm_bytes);
+
+        log::info!("Create a Store");
+        // precompiled modules run on the engine of the shim that compiled them
+        let engine = if is_precompiled || compiler == self.compiler {
+            self.engine.clone()
+        } else {
+            compiler.engine()?
+        };
+        let mut store = Store::new(engine);
+
+        let module = if is_precompiled {
+            log::info!("using module precompiled with {}", self.compiler);
+            unsafe { Module::deserialize(&store, wasm_bytes) }?
+        } else {
+            log::info!("compiling module with {compiler}");
+            Module::from_binary(&store, &wasm_bytes)?
+        };
 
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
@@ -62,4 +156,218 @@ impl Engine for WasmerEngine {
 
         Ok(status)
     }
+
+    /// Check that the entrypoint is a wasm module, a precompiled module, a `wat` file,
+    /// or a webc package with the command selected by the entrypoint.
+    /// Components and unknown or disabled compilers are rejected when the container is created,
+    /// instead of failing when it starts.
+    fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        self.compiler(ctx)?;
+
+        let Entrypoint { source, func, .. } = ctx.entrypoint();
+
+        if let Source::Oci(layers) = source {
+            if let Some(version) = WasiVersion::from_platform(ctx.platform()) {
+                if !Self::supported_platforms().contains(&version.os()) {
+                    bail!("{} doesn't support the {version} platform", Self::name());
+                }
+            }
+            if let Some(layer) = layers.iter().find(|layer| is_webc_layer(layer)) {
+                let container = Container::from_bytes(layer.layer.to_vec())
+                    .with_context(|| format!("layer {}", layer.config.digest()))?;
+                webc_command(&container, &func)?;
+
+                let dependencies: Vec<_> = container.manifest().use_map.keys().collect();
+                if !dependencies.is_empty() && !uses_registry(ctx) {
+                    bail!(
+                        "the webc package depends on {dependencies:?}, which are only loaded from the wasmer registry with the {WEBC_REGISTRY_ANNOTATION}: \"true\" annotation"
+                    );
+                }
+                return Ok(());
+            }
+        }
+
+        let wasm_bytes = source.as_bytes()?;
+        match WasmBinaryType::from_bytes(&wasm_bytes) {
+            Some(WasmBinaryType::Module) => Ok(()),
+            Some(WasmBinaryType::Component) => {
+                bail!("wasmer doesn't support wasm components, only wasm modules")
+            }
+            None if Artifact::is_deserializable(&wasm_bytes) => Ok(()),
+            None if webc::is_webc(&wasm_bytes) => bail!(
+                "the entrypoint is a webc package, which only runs from layers of type {WEBC_LAYER_MEDIA_TYPE}"
+            ),
+            None => {
+                wasmer::wat2wasm(&wasm_bytes)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn supported_layers_types() -> &'static [&'static str] {
+        &[
+            "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
+            WEBC_LAYER_MEDIA_TYPE,
+        ]
+    }
+
+    fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut compiled_layers = Vec::<Option<Vec<u8>>>::with_capacity(layers.len());
+
+        // the compiler runs on a pool of its own: the threads of the global pool
+        // don't exist in the containers forked by the shim, which would then hang
+        // when they compile a module with it
+        let pool = rayon::ThreadPoolBuilder::new().build()?;
+
+        for layer in layers {
+            if Artifact::is_deserializable(&layer.layer) {
+                log::info!("Already precompiled");
+                compiled_layers.push(None);
+                continue;
+            }
+
+            // only modules are compiled
+            if is_webc_layer(layer)
+                || WasmBinaryType::from_bytes(&layer.layer) != Some(WasmBinaryType::Module)
+            {
+                compiled_layers.push(None);
+                continue;
+            }
+
+            let module = pool.install(|| Module::from_binary(&self.engine, &layer.layer))?;
+            compiled_layers.push(Some(module.serialize()?.to_vec()));
+        }
+
+        Ok(compiled_layers)
+    }
+
+    /// The serialized modules depend on the version of wasmer, on the compiler,
+    /// and on the target they are compiled for.
+    fn can_precompile(&self) -> Option<String> {
+        let target = self.engine.target();
+        let mut hasher = DefaultHasher::new();
+        wasmer::VERSION.hash(&mut hasher);
+        self.engine.deterministic_id().hash(&mut hasher);
+        target.triple().to_string().hash(&mut hasher);
+        target.cpu_features().as_u64().hash(&mut hasher);
+        Some(hasher.finish().to_string())
+    }
+}
+
+fn is_webc_layer(layer: &WasmLayer) -> bool {
+    layer.config.media_type().to_string() == WEBC_LAYER_MEDIA_TYPE
+}
+
+fn uses_registry(ctx: &impl RuntimeContext) -> bool {
+    ctx.annotations()
+        .and_then(|a| a.get(WEBC_REGISTRY_ANNOTATION))
+        .is_some_and(|value| value == "true")
+}
+
+/// Returns the command of a webc package selected by the `func` of the entrypoint.
+/// Without a `#<command>` in the entrypoint (i.e. `_start`), this is the entrypoint command of
+/// the package, or its only command.
+fn webc_command(container: &Container, func: &str) -> Result<String> {
+    let manifest = container.manifest();
+    let name = match func {
+        "_start" => manifest
+            .entrypoint
+            .clone()
+            .or_else(|| match manifest.commands.keys().collect::<Vec<_>>()[..] {
+                [command] => Some(command.clone()),
+                _ => None,
+            })
+            .context("the webc package has no entrypoint, select a command with `#<command>`")?,
+        command => command.to_string(),
+    };
+
+    let Some(command) = manifest.commands.get(&name) else {
+        bail!(
+            "the webc package has no {name:?} command, available commands: {:?}",
+            manifest.commands.keys().collect::<Vec<_>>()
+        );
+    };
+    if !WasiRunner::can_run_command(command)? {
+        bail!("the {name:?} command of the webc package isn't a WASI command");
+    }
+
+    Ok(name)
+}
+
+/// Runs a command of a webc package with the wasix WASI runner.
+/// The volumes of the package are mounted over the filesystem of the container, and
+/// with `registry` the dependencies of the package are loaded from the wasmer registry.
+fn run_webc(
+    engine: wasmer::Engine,
+    webc: &WasmLayer,
+    func: &str,
+    args: &[String],
+    registry: bool,
+    stdio: Stdio,
+) -> Result<i32> {
+    let container = Container::from_bytes(webc.layer.to_vec())?;
+    let command = webc_command(&container, func)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let _guard = runtime.enter();
+
+    let mut rt = PluggableRuntime::new(Arc::new(TokioTaskManager::new(runtime.handle().clone())));
+    rt.set_engine(Some(engine));
+    if registry {
+        let client = default_http_client().context("no http client to load the package")?;
+        rt.set_package_loader(BuiltinPackageLoader::new_only_client(Arc::new(client)));
+    } else {
+        // only the package of the image is loaded
+        rt.set_source(MultiSource::new());
+        rt.set_package_loader(BuiltinPackageLoader::new_only_client(Arc::new(NoRegistry)));
+    }
+
+    log::info!("loading webc package");
+    let pkg = runtime.block_on(BinaryPackage::from_webc(&container, &rt))?;
+
+    let envs = std::env::vars();
+    log::info!("Creating `WasiRunner`...: args {args:?}, envs: {envs:?}");
+    let mut runner = WasiRunner::new()
+        .with_args(args.iter().skip(1))
+        .with_envs(envs)
+        .with_mapped_directories([MappedDirectory {
+            host: "/".into(),
+            guest: "/".into(),
+        }]);
+
+    log::info!("redirect stdio");
+    stdio.redirect()?;
+
+    log::info!("Running command {command:?}");
+    match runner.run_command(&command, &pkg, Arc::new(rt)) {
+        Ok(()) => Ok(0),
+        Err(err) => match err
+            .downcast_ref::<WasiRuntimeError>()
+            .and_then(WasiRuntimeError::as_exit_code)
+        {
+            Some(code) => Ok(code.raw()),
+            None => Err(err),
+        },
+    }
+}
+
+/// An http client that refuses every request, so that nothing is downloaded from the wasmer
+/// registry without the [`WEBC_REGISTRY_ANNOTATION`].
+#[derive(Debug)]
+struct NoRegistry;
+
+impl HttpClient for NoRegistry {
+    fn request(
+        &self,
+        request: HttpRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<HttpResponse>> + Send + '_>> {
+        Box::pin(async move {
+            bail!(
+                "can't load {} without the {WEBC_REGISTRY_ANNOTATION} annotation",
+                request.url
+            )
+        })
+    }
 }
@@ -1,3 +1,4 @@
+pub mod compiler;
 pub mod instance;
 
 pub use instance::WasmerInstance;
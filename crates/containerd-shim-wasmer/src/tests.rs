@@ -1,40 +1,53 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use std::time::Duration;
 
+use containerd_shim_wasm::container::{Engine, Entrypoint, RuntimeContext, Source, Stdio};
+use containerd_shim_wasm::sandbox::WasmLayer;
+
 //use containerd_shim_wasm::sandbox::Instance;
 use containerd_shim_wasm::testing::modules::*;
-use containerd_shim_wasm::testing::WasiTest;
+use containerd_shim_wasm::testing::{oci_helpers, WasiTest};
+use oci_spec::image::{Descriptor, MediaType, Platform};
 use serial_test::serial;
 
-use crate::instance::WasmerInstance as WasiInstance;
+use crate::compiler::{Compiler, COMPILER_ANNOTATION};
+use crate::instance::{
+    WasmerEngine, WasmerInstance as WasiInstance, WEBC_LAYER_MEDIA_TYPE, WEBC_REGISTRY_ANNOTATION,
+};
 
-#[test]
-#[serial]
-fn test_delete_after_create() -> anyhow::Result<()> {
-    WasiTest::<WasiInstance>::builder()?.build()?.delete()?;
-    Ok(())
-}
+containerd_shim_wasm::conformance_tests!(WasiInstance);
 
 #[test]
 #[serial]
-fn test_hello_world() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+fn test_hello_world_oci_uses_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
         .with_wasm(HELLO_WORLD)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c1".to_string()),
+        )?;
+
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
     assert_eq!(stdout, "hello world\n");
 
-    Ok(())
-}
+    let (label, _id) = oci_helpers::get_content_label()?;
+    assert!(
+        label.starts_with("runwasi.io/precompiled/wasmer/"),
+        "was {}",
+        label
+    );
 
-#[test]
-#[serial]
-fn test_hello_world_oci() -> anyhow::Result<()> {
-    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+    // run second time, it should succeed without recompiling
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
         .with_wasm(HELLO_WORLD)?
-        .as_oci_image(None, None)?;
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c2".to_string()),
+        )?;
 
     let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
 
@@ -45,74 +58,252 @@ fn test_hello_world_oci() -> anyhow::Result<()> {
 }
 
 #[test]
-#[serial]
-fn test_custom_entrypoint() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_start_fn("foo")?
-        .with_wasm(CUSTOM_ENTRYPOINT)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_compiler_from_str() -> anyhow::Result<()> {
+    assert_eq!("cranelift".parse::<Compiler>()?, Compiler::Cranelift);
+    assert_eq!("singlepass".parse::<Compiler>()?, Compiler::Singlepass);
+    assert_eq!("llvm".parse::<Compiler>()?, Compiler::Llvm);
+    assert!("v8".parse::<Compiler>().is_err());
 
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "hello world\n");
+    // only the compilers enabled with cargo features can create an engine
+    assert!(Compiler::build_default().engine().is_ok());
+    assert_eq!(
+        Compiler::Singlepass.engine().is_ok(),
+        cfg!(feature = "singlepass")
+    );
 
     Ok(())
 }
 
+struct OciContext {
+    layers: Vec<WasmLayer>,
+    platform: Platform,
+    func: String,
+    annotations: HashMap<String, String>,
+}
+
+impl OciContext {
+    fn new(media_type: &str, layer: Vec<u8>) -> Self {
+        let config = Descriptor::new(MediaType::Other(media_type.to_string()), 0, "sha256:1234");
+        Self {
+            layers: vec![WasmLayer {
+                config,
+                layer: layer.into(),
+            }],
+            platform: Platform::default(),
+            func: "_start".to_string(),
+            annotations: HashMap::new(),
+        }
+    }
+
+    fn with_func(mut self, func: &str) -> Self {
+        self.func = func.to_string();
+        self
+    }
+
+    fn with_annotation(mut self, key: &str, value: &str) -> Self {
+        self.annotations.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+impl RuntimeContext for OciContext {
+    fn args(&self) -> &[String] {
+        &[]
+    }
+
+    fn entrypoint(&self) -> Entrypoint {
+        Entrypoint {
+            func: self.func.clone(),
+            name: None,
+            arg0: None,
+            source: Source::Oci(&self.layers),
+        }
+    }
+
+    fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    fn annotations(&self) -> Option<&HashMap<String, String>> {
+        Some(&self.annotations)
+    }
+}
+
 #[test]
-#[serial]
-fn test_unreachable() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(UNREACHABLE)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_can_handle_checks_the_compiler_annotation() -> anyhow::Result<()> {
+    const WASM_LAYER: &str = "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm";
+    let engine = WasmerEngine::default();
+    let module = || OciContext::new(WASM_LAYER, wat::parse_str("(module)").unwrap());
+
+    let ctx = module().with_annotation(COMPILER_ANNOTATION, &Compiler::build_default().to_string());
+    engine.can_handle(&ctx)?;
 
-    assert_ne!(exit_code, 0);
+    let ctx = module().with_annotation(COMPILER_ANNOTATION, "v8");
+    let err = engine.can_handle(&ctx).unwrap_err();
+    assert!(
+        format!("{err:#}").contains("unknown wasmer compiler \"v8\""),
+        "{err:#}"
+    );
+
+    let ctx = module().with_annotation(COMPILER_ANNOTATION, "singlepass");
+    match engine.can_handle(&ctx) {
+        Ok(()) => assert!(cfg!(feature = "singlepass")),
+        Err(err) => assert_eq!(
+            err.to_string(),
+            "the singlepass compiler is not enabled in this build of the shim"
+        ),
+    }
 
     Ok(())
 }
 
 #[test]
-#[serial]
-fn test_exit_code() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(EXIT_CODE)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_can_handle_rejects_components() -> anyhow::Result<()> {
+    const WASM_LAYER: &str = "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm";
+    let engine = WasmerEngine::default();
+
+    let module = OciContext::new(WASM_LAYER, wat::parse_str("(module)")?);
+    engine.can_handle(&module)?;
+
+    let component = OciContext::new(WASM_LAYER, wat::parse_str("(component)")?);
+    let err = engine.can_handle(&component).unwrap_err();
+    assert!(err.to_string().contains("wasm components"), "{err}");
+
+    // webc packages only run from the layers of their media type
+    let dir = tempfile::tempdir()?;
+    let webc = OciContext::new(WASM_LAYER, webc_package(dir.path())?);
+    let err = engine.can_handle(&webc).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the entrypoint is a webc package, which only runs from layers of type application/webc"
+    );
+
+    Ok(())
+}
+
+// Builds a webc package with an `answer` entrypoint command, that exits with 42
+// when the file of its `/assets` volume exists, and an `exit` command that exits with 7.
+fn webc_package(dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let answer = wat::parse_str(
+        r#"(module
+            (import "wasi_snapshot_preview1" "path_open"
+                (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "assets/answer.txt")
+            (func (export "_start")
+                (local $errno i32)
+                ;; fd 4 is the "/" preopen, after the virtual root of wasix
+                (local.set $errno (call $path_open
+                    (i32.const 4) (i32.const 0) (i32.const 16) (i32.const 17)
+                    (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 0)))
+                (call $exit (select
+                    (i32.const 42)
+                    (i32.add (i32.const 100) (local.get $errno))
+                    (i32.eqz (local.get $errno))))))"#,
+    )?;
+    let exit = wat::parse_str(
+        r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (call $exit (i32.const 7))))"#,
+    )?;
+
+    fs::write(dir.join("answer.wasm"), answer)?;
+    fs::write(dir.join("exit.wasm"), exit)?;
+    fs::create_dir(dir.join("assets"))?;
+    fs::write(dir.join("assets").join("answer.txt"), "42")?;
+    fs::write(
+        dir.join("wasmer.toml"),
+        r#"
+            [package]
+            name = "runwasi/test"
+            version = "0.1.0"
+            description = "Test package"
+            entrypoint = "answer"
+
+            [[module]]
+            name = "answer"
+            source = "answer.wasm"
+            abi = "wasi"
+
+            [[module]]
+            name = "exit"
+            source = "exit.wasm"
+            abi = "wasi"
+
+            [[command]]
+            name = "answer"
+            module = "answer"
 
-    assert_eq!(exit_code, 42);
+            [[command]]
+            name = "exit"
+            module = "exit"
+
+            [fs]
+            "/assets" = "assets"
+        "#,
+    )?;
+
+    let package = webc::wasmer_package::Package::from_manifest(dir.join("wasmer.toml"))?;
+    Ok(package.serialize()?.to_vec())
+}
+
+#[test]
+fn test_can_handle_webc_commands() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let webc = webc_package(dir.path())?;
+    let engine = WasmerEngine::default();
+
+    engine.can_handle(&OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc.clone()))?;
+    engine.can_handle(&OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc.clone()).with_func("exit"))?;
+
+    let ctx = OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc).with_func("missing");
+    let err = engine.can_handle(&ctx).unwrap_err();
+    assert!(err.to_string().contains("no \"missing\" command"), "{err}");
 
     Ok(())
 }
 
 #[test]
-#[serial]
-fn test_seccomp() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SECCOMP)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_can_handle_webc_dependencies() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    webc_package(dir.path())?;
+    let mut manifest = fs::read_to_string(dir.path().join("wasmer.toml"))?;
+    manifest.push_str("[dependencies]\n\"runwasi/dependency\" = \"0.1.0\"\n");
+    fs::write(dir.path().join("wasmer.toml"), manifest)?;
+    let package = webc::wasmer_package::Package::from_manifest(dir.path().join("wasmer.toml"))?;
+    let webc = package.serialize()?.to_vec();
+    let engine = WasmerEngine::default();
 
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout.trim(), "current working dir: /");
+    // the dependencies are not in the image, they are only loaded from the registry on demand
+    let ctx = OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc.clone());
+    let err = engine.can_handle(&ctx).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("the webc package depends on [\"runwasi/dependency\"], which are only loaded from the wasmer registry with the {WEBC_REGISTRY_ANNOTATION}: \"true\" annotation")
+    );
+
+    let ctx = OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc)
+        .with_annotation(WEBC_REGISTRY_ANNOTATION, "true");
+    engine.can_handle(&ctx)?;
 
     Ok(())
 }
 
 #[test]
-#[serial]
-fn test_has_default_devices() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HAS_DEFAULT_DEVICES)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_run_webc() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let webc = webc_package(dir.path())?;
+    let engine = WasmerEngine::default();
 
-    assert_eq!(exit_code, 0);
+    // runs the entrypoint of the package, with its volumes mounted
+    let ctx = OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc.clone());
+    assert_eq!(engine.run_wasi(&ctx, Stdio::default())?, 42);
+
+    // runs the command selected with `#<command>`
+    let ctx = OciContext::new(WEBC_LAYER_MEDIA_TYPE, webc).with_func("exit");
+    assert_eq!(engine.run_wasi(&ctx, Stdio::default())?, 7);
 
     Ok(())
 }
@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::{RuntimeContext, Source};
+use wasmtime::component::Linker;
+
+use super::bindings::wasi::config::runtime::{self, ConfigError};
+use crate::instance::{HostInterface, WasiCtx};
+
+/// Media type of the OCI layers with the runtime config of a component,
+/// a JSON object of string values.
+pub const WASI_CONFIG_LAYER_MEDIA_TYPE: &str = "application/vnd.runwasi.wasi.config.v1+json";
+
+/// Prefix of the annotations that set a runtime config value of a component,
+/// e.g. `wasi-config.runwasi.io/log-level: debug` sets the `log-level` key.
+/// Annotations take precedence over the values of a config layer.
+pub const WASI_CONFIG_ANNOTATION_PREFIX: &str = "wasi-config.runwasi.io/";
+
+/// Provides `wasi:config/runtime` to components, from the config layers
+/// and annotations of the container.
+#[derive(Default)]
+pub struct RuntimeConfigInterface;
+
+/// The runtime config of a container.
+struct RuntimeConfig(BTreeMap<String, String>);
+
+impl RuntimeConfig {
+    fn from_context(ctx: &dyn RuntimeContext) -> Result<Self> {
+        let mut values = BTreeMap::new();
+
+        if let Source::Oci(layers) = ctx.entrypoint().source {
+            let config_layers = layers.iter().filter(|layer| {
+                layer.config.media_type().to_string() == WASI_CONFIG_LAYER_MEDIA_TYPE
+            });
+            for layer in config_layers {
+                let layer_values: HashMap<String, String> = serde_json::from_slice(&layer.layer)
+                    .with_context(|| format!("invalid config layer {}", layer.config.digest()))?;
+                values.extend(layer_values);
+            }
+        }
+
+        let annotations = ctx.annotations().into_iter().flatten();
+        values.extend(annotations.filter_map(|(key, value)| {
+            let key = key.strip_prefix(WASI_CONFIG_ANNOTATION_PREFIX)?;
+            Some((key.to_string(), value.clone()))
+        }));
+
+        Ok(Self(values))
+    }
+}
+
+impl HostInterface for RuntimeConfigInterface {
+    fn prepare_context(&self, ctx: &dyn RuntimeContext, wasi_ctx: &mut WasiCtx) -> Result<()> {
+        wasi_ctx.insert_extension(RuntimeConfig::from_context(ctx)?);
+        Ok(())
+    }
+
+    fn add_to_component_linker(&self, linker: &mut Linker<WasiCtx>) -> Result<()> {
+        runtime::add_to_linker(linker, |ctx| ctx)
+    }
+}
+
+impl runtime::Host for WasiCtx {
+    fn get(&mut self, key: String) -> wasmtime::Result<Result<Option<String>, ConfigError>> {
+        let value = self
+            .extension::<RuntimeConfig>()
+            .and_then(|RuntimeConfig(values)| values.get(&key).cloned());
+        Ok(Ok(value))
+    }
+
+    fn get_all(&mut self) -> wasmtime::Result<Result<Vec<(String, String)>, ConfigError>> {
+        let values = self
+            .extension::<RuntimeConfig>()
+            .map(|RuntimeConfig(values)| values.clone().into_iter().collect())
+            .unwrap_or_default();
+        Ok(Ok(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim_wasm::container::Entrypoint;
+    use containerd_shim_wasm::sandbox::WasmLayer;
+    use oci_spec::image::{Descriptor, MediaType, Platform};
+
+    use super::*;
+
+    struct TestContext {
+        layers: Vec<WasmLayer>,
+        annotations: HashMap<String, String>,
+        platform: Platform,
+    }
+
+    impl RuntimeContext for TestContext {
+        fn args(&self) -> &[String] {
+            &[]
+        }
+
+        fn entrypoint(&self) -> Entrypoint {
+            Entrypoint {
+                func: "_start".to_string(),
+                name: None,
+                arg0: None,
+                source: Source::Oci(&self.layers),
+            }
+        }
+
+        fn platform(&self) -> &Platform {
+            &self.platform
+        }
+
+        fn annotations(&self) -> Option<&HashMap<String, String>> {
+            Some(&self.annotations)
+        }
+    }
+
+    #[test]
+    fn test_runtime_config_from_context() -> Result<()> {
+        let config_layer = WasmLayer {
+            config: Descriptor::new(
+                MediaType::Other(WASI_CONFIG_LAYER_MEDIA_TYPE.to_string()),
+                0,
+                "sha256:1234",
+            ),
+            layer: br#"{"log-level": "info", "name": "app"}"#.to_vec().into(),
+        };
+        let ctx = TestContext {
+            layers: vec![config_layer],
+            annotations: HashMap::from([
+                (
+                    "wasi-config.runwasi.io/log-level".to_string(),
+                    "debug".to_string(),
+                ),
+                ("other".to_string(), "ignored".to_string()),
+            ]),
+            platform: Platform::default(),
+        };
+
+        let RuntimeConfig(values) = RuntimeConfig::from_context(&ctx)?;
+        assert_eq!(
+            values,
+            BTreeMap::from([
+                ("log-level".to_string(), "debug".to_string()),
+                ("name".to_string(), "app".to_string()),
+            ])
+        );
+
+        Ok(())
+    }
+}
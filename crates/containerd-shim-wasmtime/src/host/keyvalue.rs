@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use containerd_shim_wasm::container::RuntimeContext;
+use wasmtime::component::{Linker, Resource};
+
+use super::bindings::wasi::keyvalue::store::{self, Error, KeyResponse};
+use super::bindings::wasi::keyvalue::{atomics, batch};
+use crate::instance::{HostInterface, WasiCtx};
+
+/// Directory of the buckets in the root filesystem of the container,
+/// so that they are stored in its writable layer.
+pub const DEFAULT_KEYVALUE_DIR: &str = "/.wasi-keyvalue";
+
+/// Provides `wasi:keyvalue` to components, with each bucket stored as a JSON file in a directory of the container.
+pub struct KeyValueInterface {
+    dir: PathBuf,
+}
+
+impl KeyValueInterface {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl Default for KeyValueInterface {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEYVALUE_DIR)
+    }
+}
+
+impl HostInterface for KeyValueInterface {
+    fn prepare_context(&self, _ctx: &dyn RuntimeContext, wasi_ctx: &mut WasiCtx) -> Result<()> {
+        wasi_ctx.insert_extension(KeyValueStore {
+            dir: self.dir.clone(),
+        });
+        Ok(())
+    }
+
+    fn add_to_component_linker(&self, linker: &mut Linker<WasiCtx>) -> Result<()> {
+        store::add_to_linker(linker, |ctx| ctx)?;
+        atomics::add_to_linker(linker, |ctx| ctx)?;
+        batch::add_to_linker(linker, |ctx| ctx)?;
+        Ok(())
+    }
+}
+
+/// The directory of the buckets of a container.
+struct KeyValueStore {
+    dir: PathBuf,
+}
+
+/// An open bucket, the entries are read from its file on every operation so that
+/// the buckets opened more than once see the changes of each other.
+pub struct Bucket {
+    path: PathBuf,
+}
+
+type Entries = BTreeMap<String, Vec<u8>>;
+type KeyValue = (String, Vec<u8>);
+
+impl Bucket {
+    fn open(dir: &Path, identifier: &str) -> Result<Self, Error> {
+        let is_valid = !identifier.is_empty()
+            && !identifier.starts_with('.')
+            && identifier
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !is_valid {
+            return Err(Error::NoSuchStore);
+        }
+        fs::create_dir_all(dir).map_err(other)?;
+        Ok(Self {
+            path: dir.join(format!("{identifier}.json")),
+        })
+    }
+
+    fn read(&self) -> Result<Entries, Error> {
+        match fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(other),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Entries::new()),
+            Err(err) => Err(other(err)),
+        }
+    }
+
+    fn write(&self, entries: &Entries) -> Result<(), Error> {
+        // write to a temporary file first so that the bucket is never left half written
+        let tmp_path = self.path.with_extension("json.tmp");
+        let content = serde_json::to_vec(entries).map_err(other)?;
+        fs::write(&tmp_path, content).map_err(other)?;
+        fs::rename(&tmp_path, &self.path).map_err(other)
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Entries) -> Result<T, Error>) -> Result<T, Error> {
+        let mut entries = self.read()?;
+        let result = f(&mut entries)?;
+        self.write(&entries)?;
+        Ok(result)
+    }
+
+    fn increment(&self, key: String, delta: u64) -> Result<u64, Error> {
+        self.update(|entries| {
+            let value = match entries.get(&key) {
+                Some(value) => {
+                    let value = value.as_slice().try_into().map_err(|_| {
+                        Error::Other(format!("value of key {key:?} is not a 64-bit integer"))
+                    })?;
+                    u64::from_le_bytes(value)
+                }
+                None => 0,
+            };
+            let value = value.wrapping_add(delta);
+            entries.insert(key, value.to_le_bytes().to_vec());
+            Ok(value)
+        })
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<KeyValue>>, Error> {
+        let entries = self.read()?;
+        Ok(keys
+            .into_iter()
+            .map(|key| entries.get(&key).cloned().map(|value| (key, value)))
+            .collect())
+    }
+}
+
+fn other(err: impl std::fmt::Display) -> Error {
+    Error::Other(err.to_string())
+}
+
+impl WasiCtx {
+    fn bucket(&self, bucket: &Resource<Bucket>) -> wasmtime::Result<&Bucket> {
+        Ok(self.resource_table.get(bucket)?)
+    }
+}
+
+impl store::Host for WasiCtx {
+    fn open(&mut self, identifier: String) -> wasmtime::Result<Result<Resource<Bucket>, Error>> {
+        let Some(KeyValueStore { dir }) = self.extension::<KeyValueStore>() else {
+            return Ok(Err(Error::AccessDenied));
+        };
+        let bucket = match Bucket::open(dir, &identifier) {
+            Ok(bucket) => bucket,
+            Err(err) => return Ok(Err(err)),
+        };
+        Ok(Ok(self.resource_table.push(bucket)?))
+    }
+}
+
+impl store::HostBucket for WasiCtx {
+    fn get(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> wasmtime::Result<Result<Option<Vec<u8>>, Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.read().map(|mut entries| entries.remove(&key)))
+    }
+
+    fn set(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        value: Vec<u8>,
+    ) -> wasmtime::Result<Result<(), Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.update(|entries| {
+            entries.insert(key, value);
+            Ok(())
+        }))
+    }
+
+    fn delete(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> wasmtime::Result<Result<(), Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.update(|entries| {
+            entries.remove(&key);
+            Ok(())
+        }))
+    }
+
+    fn exists(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> wasmtime::Result<Result<bool, Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.read().map(|entries| entries.contains_key(&key)))
+    }
+
+    fn list_keys(
+        &mut self,
+        bucket: Resource<Bucket>,
+        cursor: Option<u64>,
+    ) -> wasmtime::Result<Result<KeyResponse, Error>> {
+        let bucket = self.bucket(&bucket)?;
+        // all the keys are returned at once, the cursor is the number of keys to skip
+        let skip = cursor.unwrap_or_default() as usize;
+        Ok(bucket.read().map(|entries| KeyResponse {
+            keys: entries.into_keys().skip(skip).collect(),
+            cursor: None,
+        }))
+    }
+
+    fn drop(&mut self, bucket: Resource<Bucket>) -> wasmtime::Result<()> {
+        self.resource_table.delete(bucket)?;
+        Ok(())
+    }
+}
+
+impl atomics::Host for WasiCtx {
+    fn increment(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        delta: u64,
+    ) -> wasmtime::Result<Result<u64, Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.increment(key, delta))
+    }
+}
+
+impl batch::Host for WasiCtx {
+    fn get_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> wasmtime::Result<Result<Vec<Option<(String, Vec<u8>)>>, Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.get_many(keys))
+    }
+
+    fn set_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key_values: Vec<(String, Vec<u8>)>,
+    ) -> wasmtime::Result<Result<(), Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.update(|entries| {
+            entries.extend(key_values);
+            Ok(())
+        }))
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> wasmtime::Result<Result<(), Error>> {
+        let bucket = self.bucket(&bucket)?;
+        Ok(bucket.update(|entries| {
+            for key in keys {
+                entries.remove(&key);
+            }
+            Ok(())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        assert!(matches!(
+            Bucket::open(dir.path(), "../escape"),
+            Err(Error::NoSuchStore)
+        ));
+
+        let bucket = Bucket::open(dir.path(), "default").map_err(anyhow::Error::msg)?;
+        assert!(bucket.read().map_err(anyhow::Error::msg)?.is_empty());
+
+        bucket
+            .update(|entries| {
+                entries.insert("key".to_string(), b"value".to_vec());
+                Ok(())
+            })
+            .map_err(anyhow::Error::msg)?;
+
+        // another handle of the same bucket sees the changes
+        let other = Bucket::open(dir.path(), "default").map_err(anyhow::Error::msg)?;
+        let entries = other.read().map_err(anyhow::Error::msg)?;
+        assert_eq!(entries.get("key"), Some(&b"value".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_increment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bucket = Bucket::open(dir.path(), "counters").map_err(anyhow::Error::msg)?;
+
+        assert_eq!(
+            bucket
+                .increment("count".to_string(), 2)
+                .map_err(anyhow::Error::msg)?,
+            2
+        );
+        assert_eq!(
+            bucket
+                .increment("count".to_string(), 3)
+                .map_err(anyhow::Error::msg)?,
+            5
+        );
+
+        bucket
+            .update(|entries| {
+                entries.insert("text".to_string(), b"text".to_vec());
+                Ok(())
+            })
+            .map_err(anyhow::Error::msg)?;
+        assert!(matches!(
+            bucket.increment("text".to_string(), 1),
+            Err(Error::Other(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_get_many() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bucket = Bucket::open(dir.path(), "default").map_err(anyhow::Error::msg)?;
+        bucket
+            .update(|entries| {
+                entries.insert("key".to_string(), b"value".to_vec());
+                Ok(())
+            })
+            .map_err(anyhow::Error::msg)?;
+
+        // a key that is requested twice is returned twice
+        let keys = ["key", "missing", "key"].map(String::from).to_vec();
+        let values = bucket.get_many(keys).map_err(anyhow::Error::msg)?;
+        let key = Some(("key".to_string(), b"value".to_vec()));
+        assert_eq!(values, [key.clone(), None, key]);
+
+        Ok(())
+    }
+}
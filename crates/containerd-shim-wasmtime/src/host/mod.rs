@@ -0,0 +1,24 @@
+//! Host implementations of the WASI proposals the wasmtime shim provides in addition to
+//! `wasmtime-wasi`, as [`HostInterface`](crate::instance::HostInterface)s of the engine.
+
+mod config;
+mod keyvalue;
+#[cfg(feature = "wasi_nn")]
+mod nn;
+
+pub use config::{
+    RuntimeConfigInterface, WASI_CONFIG_ANNOTATION_PREFIX, WASI_CONFIG_LAYER_MEDIA_TYPE,
+};
+pub use keyvalue::{KeyValueInterface, DEFAULT_KEYVALUE_DIR};
+#[cfg(feature = "wasi_nn")]
+pub use nn::{WasiNnInterface, WASI_NN_MODEL_LAYER_MEDIA_TYPE, WASI_NN_MODEL_NAME_ANNOTATION};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit",
+        world: "runwasi:host/host",
+        with: {
+            "wasi:keyvalue/store/bucket": super::keyvalue::Bucket,
+        },
+    });
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::{RuntimeContext, Source};
+use containerd_shim_wasm::sandbox::WasmLayer;
+use wasmtime::component::Linker;
+use wasmtime_wasi_nn::backend::{self, BackendError, BackendFromDir, BackendInner};
+use wasmtime_wasi_nn::wit::types::{ExecutionTarget, GraphEncoding};
+use wasmtime_wasi_nn::{Backend, Graph, GraphRegistry, Registry, WasiNnCtx};
+
+use crate::instance::{HostInterface, WasiCtx};
+
+/// Media type of the OCI layers with a model to preload, a tar archive with the
+/// `model.xml` and `model.bin` files of an OpenVINO model.
+pub const WASI_NN_MODEL_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.runwasi.wasi-nn.openvino.model.v1.tar";
+
+/// Annotation of a model layer with the name the guest loads the model by,
+/// `default` when it's not set.
+pub const WASI_NN_MODEL_NAME_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Provides `wasi-nn` to modules and components, with the OpenVINO backend run on the CPU.
+///
+/// The OpenVINO libraries of the node are not visible from inside the container, so the backend
+/// is loaded by the shim, together with the models of the image, before the container is created.
+/// The loaded models are kept by layer digest and shared by the containers of the shim.
+#[derive(Default)]
+pub struct WasiNnInterface {
+    backend: CpuBackend,
+    models: Mutex<HashMap<String, Graph>>,
+}
+
+impl HostInterface for WasiNnInterface {
+    fn prepare(&self, layers: &[WasmLayer]) -> Result<()> {
+        let mut models = self.models.lock().unwrap();
+        for layer in model_layers(layers) {
+            let digest = layer.config.digest();
+            if models.contains_key(digest) {
+                continue;
+            }
+
+            let model = Model::from_layer(layer)?;
+            log::info!("loading wasi-nn model {:?} from layer {digest}", model.name);
+            let graph = self
+                .backend
+                .clone()
+                .load(&[&model.xml, &model.weights], ExecutionTarget::Cpu)
+                .with_context(|| format!("failed to load model {:?}", model.name))?;
+            models.insert(digest.clone(), graph);
+        }
+        Ok(())
+    }
+
+    fn prepare_context(&self, ctx: &dyn RuntimeContext, wasi_ctx: &mut WasiCtx) -> Result<()> {
+        let mut registry = ModelRegistry::default();
+
+        if let Source::Oci(layers) = ctx.entrypoint().source {
+            let models = self.models.lock().unwrap();
+            for layer in model_layers(layers) {
+                let name = model_name(layer);
+                let graph = models.get(layer.config.digest()).with_context(|| {
+                    format!("model {name:?} was not loaded before the container was created")
+                })?;
+                registry.0.insert(name.to_string(), graph.clone());
+            }
+        }
+
+        let backends = [Backend::from(self.backend.clone())];
+        wasi_ctx.insert_extension(WasiNnCtx::new(backends, Registry::from(registry)));
+        Ok(())
+    }
+
+    fn add_to_linker(&self, linker: &mut wasmtime::Linker<WasiCtx>) -> Result<()> {
+        wasmtime_wasi_nn::witx::add_to_linker(linker, wasi_nn_ctx)
+    }
+
+    fn add_to_component_linker(&self, linker: &mut Linker<WasiCtx>) -> Result<()> {
+        wasmtime_wasi_nn::wit::ML::add_to_linker(linker, wasi_nn_ctx)
+    }
+}
+
+fn wasi_nn_ctx(ctx: &mut WasiCtx) -> &mut WasiNnCtx {
+    ctx.extension_mut()
+        .expect("the wasi-nn context is prepared before the container runs")
+}
+
+fn model_layers(layers: &[WasmLayer]) -> impl Iterator<Item = &WasmLayer> {
+    layers
+        .iter()
+        .filter(|layer| layer.config.media_type().to_string() == WASI_NN_MODEL_LAYER_MEDIA_TYPE)
+}
+
+fn model_name(layer: &WasmLayer) -> &str {
+    layer
+        .config
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(WASI_NN_MODEL_NAME_ANNOTATION))
+        .map_or("default", String::as_str)
+}
+
+/// The OpenVINO backend, shared by the shim and its containers.
+/// It runs the graphs on the CPU, whatever the execution target requested by the guest.
+#[derive(Clone)]
+struct CpuBackend(Arc<Mutex<Backend>>);
+
+impl Default for CpuBackend {
+    fn default() -> Self {
+        let backend = backend::list()
+            .into_iter()
+            .find(|backend| backend.encoding() == GraphEncoding::Openvino)
+            .expect("wasmtime-wasi-nn provides the OpenVINO backend");
+        Self(Arc::new(Mutex::new(backend)))
+    }
+}
+
+impl BackendInner for CpuBackend {
+    fn encoding(&self) -> GraphEncoding {
+        GraphEncoding::Openvino
+    }
+
+    fn load(
+        &mut self,
+        builders: &[&[u8]],
+        _target: ExecutionTarget,
+    ) -> Result<Graph, BackendError> {
+        self.0.lock().unwrap().load(builders, ExecutionTarget::Cpu)
+    }
+
+    fn as_dir_loadable(&mut self) -> Option<&mut dyn BackendFromDir> {
+        None
+    }
+}
+
+/// The files of an OpenVINO model layer.
+struct Model {
+    name: String,
+    xml: Vec<u8>,
+    weights: Vec<u8>,
+}
+
+impl Model {
+    fn from_layer(layer: &WasmLayer) -> Result<Self> {
+        let name = model_name(layer);
+
+        let mut files = HashMap::new();
+        let mut archive = tar::Archive::new(layer.layer.reader());
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?;
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let file_name = file_name.to_string();
+            let mut content = vec![];
+            entry.read_to_end(&mut content)?;
+            files.insert(file_name, content);
+        }
+
+        let xml = files
+            .remove("model.xml")
+            .with_context(|| format!("model {name:?} doesn't have a model.xml file"))?;
+        let weights = files
+            .remove("model.bin")
+            .with_context(|| format!("model {name:?} doesn't have a model.bin file"))?;
+        Ok(Self {
+            name: name.to_string(),
+            xml,
+            weights,
+        })
+    }
+}
+
+/// The models preloaded from the layers of the image, by name.
+#[derive(Default)]
+struct ModelRegistry(HashMap<String, Graph>);
+
+impl GraphRegistry for ModelRegistry {
+    fn get_mut(&mut self, name: &str) -> Option<&mut Graph> {
+        self.0.get_mut(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{Descriptor, MediaType};
+
+    use super::*;
+
+    fn model_layer(files: &[(&str, &[u8])], name: Option<&str>) -> Result<WasmLayer> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *content)?;
+        }
+        let archive = builder.into_inner()?;
+
+        let mut config = Descriptor::new(
+            MediaType::Other(WASI_NN_MODEL_LAYER_MEDIA_TYPE.to_string()),
+            archive.len() as i64,
+            "sha256:1234",
+        );
+        if let Some(name) = name {
+            config.set_annotations(Some(HashMap::from([(
+                WASI_NN_MODEL_NAME_ANNOTATION.to_string(),
+                name.to_string(),
+            )])));
+        }
+        Ok(WasmLayer {
+            config,
+            layer: archive.into(),
+        })
+    }
+
+    #[test]
+    fn test_model_from_layer() -> Result<()> {
+        let layer = model_layer(
+            &[
+                ("fixture/model.xml", b"<net/>"),
+                ("fixture/model.bin", b"weights"),
+                ("fixture/README.md", b"readme"),
+            ],
+            Some("mobilenet"),
+        )?;
+
+        let model = Model::from_layer(&layer)?;
+        assert_eq!(model.name, "mobilenet");
+        assert_eq!(model.xml, b"<net/>");
+        assert_eq!(model.weights, b"weights");
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_from_layer_without_weights() -> Result<()> {
+        let layer = model_layer(&[("model.xml", b"<net/>")], None)?;
+
+        let err = Model::from_layer(&layer)
+            .err()
+            .expect("the layer has no model.bin");
+        assert_eq!(
+            err.to_string(),
+            r#"model "default" doesn't have a model.bin file"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_layers_are_selected_by_media_type() -> Result<()> {
+        let config_layer = WasmLayer {
+            config: Descriptor::new(MediaType::Other("application/wasm".to_string()), 0, ""),
+            layer: vec![].into(),
+        };
+        let layers = [config_layer, model_layer(&[], Some("model"))?];
+
+        let names: Vec<_> = model_layers(&layers).map(model_name).collect();
+        assert_eq!(names, ["model"]);
+        Ok(())
+    }
+}
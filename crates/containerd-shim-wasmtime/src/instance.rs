@@ -1,11 +1,17 @@
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use containerd_shim_wasm::container::{
-    Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasmBinaryType,
+    ComponentWorld, Engine, Entrypoint, Instance, ModuleCache, ModuleCacheKey, RuntimeContext,
+    Source, Stdio, WasiVersion, WasmBinaryType,
 };
 use containerd_shim_wasm::sandbox::WasmLayer;
 use wasi_common::I32Exit;
@@ -14,14 +20,43 @@ use wasmtime::{Config, Module, Precompiled, Store};
 use wasmtime_wasi::preview2::{self as wasi_preview2};
 use wasmtime_wasi::{self as wasi_preview1, Dir};
 
+use crate::host::{KeyValueInterface, RuntimeConfigInterface, WASI_CONFIG_LAYER_MEDIA_TYPE};
+#[cfg(feature = "wasi_nn")]
+use crate::host::{WasiNnInterface, WASI_NN_MODEL_LAYER_MEDIA_TYPE};
+
 pub type WasmtimeInstance = Instance<WasmtimeEngine<DefaultConfig>>;
 
+/// Media types of the layers that are read by the host interfaces instead of being run.
+const HOST_LAYERS_TYPES: &[&str] = &[
+    WASI_CONFIG_LAYER_MEDIA_TYPE,
+    #[cfg(feature = "wasi_nn")]
+    WASI_NN_MODEL_LAYER_MEDIA_TYPE,
+];
+
 #[derive(Clone)]
 pub struct WasmtimeEngine<T: WasiConfig> {
     engine: wasmtime::Engine,
+    cache: ModuleCache<CompiledWasm>,
+    host_interfaces: Vec<Arc<dyn HostInterface>>,
     config_type: PhantomData<T>,
 }
 
+/// A compiled wasm module or component, as stored in the module cache.
+#[derive(Clone)]
+enum CompiledWasm {
+    Module(Module),
+    Component(Component),
+}
+
+impl CompiledWasm {
+    fn binary_type(&self) -> WasmBinaryType {
+        match self {
+            CompiledWasm::Module(_) => WasmBinaryType::Module,
+            CompiledWasm::Component(_) => WasmBinaryType::Component,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DefaultConfig {}
 
@@ -31,10 +66,58 @@ impl WasiConfig for DefaultConfig {
         config.wasm_component_model(true); // enable component linking
         config
     }
+
+    fn host_interfaces() -> Vec<Arc<dyn HostInterface>> {
+        vec![
+            Arc::new(KeyValueInterface::default()),
+            Arc::new(RuntimeConfigInterface),
+            #[cfg(feature = "wasi_nn")]
+            Arc::new(WasiNnInterface::default()),
+        ]
+    }
 }
 
 pub trait WasiConfig: Clone + Sync + Send + 'static {
     fn new_config() -> Config;
+
+    /// The host interfaces added to the linkers next to WASI.
+    /// They are created once, when the engine is created.
+    fn host_interfaces() -> Vec<Arc<dyn HostInterface>> {
+        vec![]
+    }
+}
+
+/// Extra host functions or WIT interfaces that a shim built on [`WasmtimeEngine`]
+/// makes available to the modules and components it runs, next to WASI.
+///
+/// The state of an interface for a container is stored in the [`WasiCtx`] with
+/// [`WasiCtx::insert_extension`], and retrieved by the host functions with
+/// [`WasiCtx::extension_mut`].
+pub trait HostInterface: Send + Sync + 'static {
+    /// Prepare the interface for the layers of an image.
+    /// This runs in the shim before the container is created, and can load host
+    /// resources that are not visible from inside the container.
+    fn prepare(&self, _layers: &[WasmLayer]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Prepare the state of the interface for the container of `ctx`.
+    fn prepare_context(&self, _ctx: &dyn RuntimeContext, _wasi_ctx: &mut WasiCtx) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add the host functions of the interface to the linker of a wasm module.
+    fn add_to_linker(&self, _linker: &mut wasmtime::Linker<WasiCtx>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Add the host interfaces of the interface to the linker of a wasm component.
+    fn add_to_component_linker(
+        &self,
+        _linker: &mut wasmtime_component::Linker<WasiCtx>,
+    ) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl<T: WasiConfig> Default for WasmtimeEngine<T> {
@@ -44,6 +127,8 @@ impl<T: WasiConfig> Default for WasmtimeEngine<T> {
             engine: wasmtime::Engine::new(&config)
                 .context("failed to create wasmtime engine")
                 .unwrap(),
+            cache: ModuleCache::default(),
+            host_interfaces: T::host_interfaces(),
             config_type: PhantomData,
         }
     }
@@ -54,6 +139,28 @@ pub struct WasiCtx {
     pub(crate) wasi_preview2: wasi_preview2::WasiCtx,
     pub(crate) wasi_preview1: wasi_preview1::WasiCtx,
     pub(crate) resource_table: ResourceTable,
+    pub(crate) preview1_adapter: wasi_preview2::preview1::WasiPreview1Adapter,
+    pub(crate) extensions: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl WasiCtx {
+    /// Store the state of a [`HostInterface`], replacing any previous value of the same type.
+    pub fn insert_extension<E: Any + Send + Sync>(&mut self, extension: E) {
+        self.extensions
+            .insert(TypeId::of::<E>(), Box::new(extension));
+    }
+
+    pub fn extension<E: Any + Send + Sync>(&self) -> Option<&E> {
+        self.extensions
+            .get(&TypeId::of::<E>())
+            .and_then(|extension| extension.downcast_ref())
+    }
+
+    pub fn extension_mut<E: Any + Send + Sync>(&mut self) -> Option<&mut E> {
+        self.extensions
+            .get_mut(&TypeId::of::<E>())
+            .and_then(|extension| extension.downcast_mut())
+    }
 }
 
 /// This impl is required to use wasmtime_wasi::preview2::WasiView trait.
@@ -75,6 +182,17 @@ impl wasmtime_wasi::preview2::WasiView for WasiCtx {
     }
 }
 
+/// This impl is required to run wasi_preview1 modules on top of wasi_preview2.
+impl wasi_preview2::preview1::WasiPreview1View for WasiCtx {
+    fn adapter(&self) -> &wasi_preview2::preview1::WasiPreview1Adapter {
+        &self.preview1_adapter
+    }
+
+    fn adapter_mut(&mut self) -> &mut wasi_preview2::preview1::WasiPreview1Adapter {
+        &mut self.preview1_adapter
+    }
+}
+
 impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
     fn name() -> &'static str {
         "wasmtime"
@@ -93,19 +211,30 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
         stdio.redirect()?;
 
         log::info!("building wasi context");
-        let wasi_ctx = prepare_wasi_ctx(ctx, envs)?;
+        let mut wasi_ctx = prepare_wasi_ctx(ctx, envs)?;
+        for host_interface in &self.host_interfaces {
+            host_interface.prepare_context(ctx, &mut wasi_ctx)?;
+        }
         let store = Store::new(&self.engine, wasi_ctx);
 
-        let wasm_bytes = &source.as_bytes()?;
-        let status = self.execute(wasm_bytes, store, func)?;
+        let compiled = self.load_source(&source)?;
+        let wasi_version = WasiVersion::from_platform(ctx.platform());
+        let status = self.execute(compiled, store, func, wasi_version)?;
 
         let status = status.map(|_| 0).or_else(|err| {
-            match err.downcast_ref::<I32Exit>() {
+            let exit_status = err
+                .downcast_ref::<I32Exit>()
+                .map(|I32Exit(status)| *status)
+                .or_else(|| {
+                    err.downcast_ref::<wasi_preview2::I32Exit>()
+                        .map(|wasi_preview2::I32Exit(status)| *status)
+                });
+            match exit_status {
                 // On Windows, exit status 3 indicates an abort (see below),
                 // so return 1 indicating a non-zero status to avoid ambiguity.
                 #[cfg(windows)]
-                Some(I32Exit(3..)) => Ok(1),
-                Some(I32Exit(status)) => Ok(*status),
+                Some(3..) => Ok(1),
+                Some(status) => Ok(status),
                 _ => Err(err),
             }
         })?;
@@ -113,10 +242,71 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
         Ok(status)
     }
 
+    /// Check that the entrypoint can be compiled and that it can run on the platform of the image.
+    /// The imports of components have to be provided by the shim, and their exports have to include
+    /// the function to run, or `wasi:cli/run` when they are run as a command.
+    fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        let Entrypoint { source, func, .. } = ctx.entrypoint();
+        let compiled = self.load_source(&source)?;
+
+        if let (Source::Oci(layers), Some(wasi_version)) =
+            (&source, WasiVersion::from_platform(ctx.platform()))
+        {
+            let media_type = wasm_layer(layers)?.config.media_type();
+            wasi_version.check_binary(compiled.binary_type(), media_type)?;
+        }
+
+        if let CompiledWasm::Component(component) = compiled {
+            // the world of precompiled components can't be inspected, but their imports are still checked
+            let wasm_binary = source_bytes(&source)?;
+            if let Some(WasmBinaryType::Component) = WasmBinaryType::from_bytes(&wasm_binary) {
+                check_component_exports(&ComponentWorld::from_bytes(&wasm_binary)?, &func)?;
+            }
+            self.component_linker()?
+                .instantiate_pre(&component)
+                .context("component imports interfaces that are not provided by the shim")?;
+        }
+
+        Ok(())
+    }
+
+    fn prepare(&self, layers: &[WasmLayer]) -> Result<()> {
+        for layer in layers {
+            let is_wasm = WasmBinaryType::from_bytes(&layer.layer).is_some()
+                || self.engine.detect_precompiled(&layer.layer).is_some();
+            if is_wasm {
+                self.load_layer(layer)?;
+            }
+        }
+        for host_interface in &self.host_interfaces {
+            host_interface.prepare(layers)?;
+        }
+        Ok(())
+    }
+
+    fn supported_layers_types() -> &'static [&'static str] {
+        &[
+            "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
+            WASI_CONFIG_LAYER_MEDIA_TYPE,
+            #[cfg(feature = "wasi_nn")]
+            WASI_NN_MODEL_LAYER_MEDIA_TYPE,
+        ]
+    }
+
+    fn supported_platforms() -> &'static [&'static str] {
+        // wasmtime runs both modules and components, prefer components when an image has both
+        &["wasip2", "wasip1"]
+    }
+
     fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
         let mut compiled_layers = Vec::<Option<Vec<u8>>>::with_capacity(layers.len());
 
         for layer in layers {
+            if !is_wasm_layer(layer) {
+                compiled_layers.push(None);
+                continue;
+            }
+
             if self.engine.detect_precompiled(&layer.layer).is_some() {
                 log::info!("Already precompiled");
                 compiled_layers.push(None);
@@ -144,15 +334,32 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
     /// to execute a wasm module that uses wasi_preview1.
+    /// When the image declares the wasip2 platform, the wasi_preview1 functions
+    /// are adapted from the wasi_preview2 implementation.
     fn execute_module(
         &self,
         module: Module,
         mut store: Store<WasiCtx>,
         func: &String,
+        wasi_version: Option<WasiVersion>,
     ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
         let mut module_linker = wasmtime::Linker::new(&self.engine);
 
-        wasi_preview1::add_to_linker(&mut module_linker, |s: &mut WasiCtx| &mut s.wasi_preview1)?;
+        match wasi_version {
+            Some(WasiVersion::Preview2) => {
+                log::info!("adapting wasi_preview1 module to wasi_preview2");
+                wasi_preview2::preview1::add_to_linker_sync(&mut module_linker)?;
+            }
+            _ => {
+                wasi_preview1::add_to_linker(&mut module_linker, |s: &mut WasiCtx| {
+                    &mut s.wasi_preview1
+                })?;
+            }
+        }
+
+        for host_interface in &self.host_interfaces {
+            host_interface.add_to_linker(&mut module_linker)?;
+        }
 
         log::info!("instantiating instance");
         let instance: wasmtime::Instance = module_linker.instantiate(&mut store, &module)?;
@@ -179,9 +386,7 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
     ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
         log::debug!("loading wasm component");
 
-        let mut linker = wasmtime_component::Linker::new(&self.engine);
-
-        wasi_preview2::command::sync::add_to_linker(&mut linker)?;
+        let linker = self.component_linker()?;
 
         log::info!("instantiating component");
 
@@ -211,39 +416,144 @@ impl<T: std::clone::Clone + Sync + WasiConfig + Send + 'static> WasmtimeEngine<T
         }
     }
 
-    fn execute(
-        &self,
-        wasm_binary: &[u8],
-        store: Store<WasiCtx>,
-        func: String,
-    ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
+    /// Create a linker with the interfaces the shim provides to components.
+    fn component_linker(&self) -> Result<wasmtime_component::Linker<WasiCtx>> {
+        let mut linker = wasmtime_component::Linker::new(&self.engine);
+        wasi_preview2::command::sync::add_to_linker(&mut linker)?;
+        for host_interface in &self.host_interfaces {
+            host_interface.add_to_component_linker(&mut linker)?;
+        }
+        Ok(linker)
+    }
+
+    /// Load the compiled entrypoint from the module cache, compiling it on a cache miss.
+    /// Entrypoints that are not OCI layers are cached by the digest of their content.
+    fn load_source(&self, source: &Source) -> Result<CompiledWasm> {
+        match source {
+            Source::Oci(layers) => self.load_layer(wasm_layer(layers)?),
+            source => {
+                let wasm_binary = source.as_bytes()?;
+                let key = ModuleCacheKey {
+                    digest: format!("sha256:{}", sha256::digest(&*wasm_binary)),
+                    precompile_key: self.can_precompile(),
+                };
+                self.cache
+                    .get_or_try_insert_with(key, wasm_binary.len(), || {
+                        self.compile(&wasm_binary, None)
+                    })
+            }
+        }
+    }
+
+    /// Load a compiled layer from the module cache, compiling it on a cache miss.
+    fn load_layer(&self, layer: &WasmLayer) -> Result<CompiledWasm> {
+        let key = ModuleCacheKey::new(self, layer);
+        self.cache
+            .get_or_try_insert_with(key, layer.layer.len(), || {
+                // the file is in the shim state directory, which is not visible from inside the container
+                let path = layer.layer.path().filter(|path| path.exists());
+                self.compile(&layer.layer, path)
+            })
+    }
+
+    /// Compile a wasm binary.
+    /// Precompiled content that is backed by the file at `path` is mapped directly from disk instead of being copied.
+    fn compile(&self, wasm_binary: &[u8], path: Option<&Path>) -> Result<CompiledWasm> {
         match WasmBinaryType::from_bytes(wasm_binary) {
             Some(WasmBinaryType::Module) => {
                 log::debug!("loading wasm module");
                 let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(module, store, &func)
+                Ok(CompiledWasm::Module(module))
             }
             Some(WasmBinaryType::Component) => {
                 let component = Component::from_binary(&self.engine, wasm_binary)?;
-                self.execute_component(component, store, func)
+                Ok(CompiledWasm::Component(component))
             }
-            None => match &self.engine.detect_precompiled(wasm_binary) {
-                Some(Precompiled::Module) => {
+            None => match (&self.engine.detect_precompiled(wasm_binary), path) {
+                (Some(Precompiled::Module), Some(path)) => {
+                    log::info!("using precompiled module from {path:?}");
+                    let module = unsafe { Module::deserialize_file(&self.engine, path) }?;
+                    Ok(CompiledWasm::Module(module))
+                }
+                (Some(Precompiled::Module), None) => {
                     log::info!("using precompiled module");
                     let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(module, store, &func)
+                    Ok(CompiledWasm::Module(module))
                 }
-                Some(Precompiled::Component) => {
+                (Some(Precompiled::Component), Some(path)) => {
+                    log::info!("using precompiled component from {path:?}");
+                    let component = unsafe { Component::deserialize_file(&self.engine, path) }?;
+                    Ok(CompiledWasm::Component(component))
+                }
+                (Some(Precompiled::Component), None) => {
                     log::info!("using precompiled component");
                     let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_component(component, store, func)
+                    Ok(CompiledWasm::Component(component))
                 }
-                None => {
+                (None, _) => {
                     bail!("invalid precompiled module")
                 }
             },
         }
     }
+
+    fn execute(
+        &self,
+        compiled: CompiledWasm,
+        store: Store<WasiCtx>,
+        func: String,
+        wasi_version: Option<WasiVersion>,
+    ) -> Result<std::prelude::v1::Result<(), anyhow::Error>, anyhow::Error> {
+        match compiled {
+            CompiledWasm::Module(module) => self.execute_module(module, store, &func, wasi_version),
+            CompiledWasm::Component(component) => self.execute_component(component, store, func),
+        }
+    }
+}
+
+/// Whether a layer of the image is a wasm module or component, and not a layer of the host interfaces.
+fn is_wasm_layer(layer: &WasmLayer) -> bool {
+    let media_type = layer.config.media_type().to_string();
+    !HOST_LAYERS_TYPES.contains(&media_type.as_str())
+}
+
+/// The layer of the module or component to run, out of the layers of the image.
+fn wasm_layer(layers: &[WasmLayer]) -> Result<&WasmLayer> {
+    match layers
+        .iter()
+        .filter(|layer| is_wasm_layer(layer))
+        .collect::<Vec<_>>()[..]
+    {
+        [layer] => Ok(layer),
+        _ => bail!("only a single module is supported when using images with OCI layers"),
+    }
+}
+
+/// The wasm binary of the entrypoint.
+fn source_bytes<'a>(source: &Source<'a>) -> Result<Cow<'a, [u8]>> {
+    match source {
+        Source::Oci(layers) => Ok(Cow::Borrowed(&wasm_layer(layers)?.layer[..])),
+        source => source.as_bytes(),
+    }
+}
+
+/// Check that a component exports the function to run,
+/// or `wasi:cli/run` when it's run as a command with the default `_start` function.
+fn check_component_exports(world: &ComponentWorld, func: &str) -> Result<()> {
+    if func == "_start" {
+        if !world.exports_interface("wasi:cli/run") {
+            bail!(
+                "component doesn't target the `wasi:cli/command` world, it doesn't export `wasi:cli/run` (exports: {:?})",
+                world.exports
+            );
+        }
+    } else if !world.exports.iter().any(|export| export == func) {
+        bail!(
+            "component doesn't export function {func:?} (exports: {:?})",
+            world.exports
+        );
+    }
+    Ok(())
 }
 
 /// Prepare both wasi_preview1 and wasi_preview2 contexts.
@@ -281,6 +591,8 @@ fn prepare_wasi_ctx(
         wasi_preview1: wasi_preview1_ctx,
         wasi_preview2: wasi_preview2_ctx,
         resource_table: ResourceTable::default(),
+        preview1_adapter: wasi_preview2::preview1::WasiPreview1Adapter::new(),
+        extensions: HashMap::new(),
     };
     Ok(wasi_data)
 }
@@ -1,3 +1,4 @@
+pub mod host;
 pub mod instance;
 
 pub use instance::WasmtimeInstance;
@@ -1,13 +1,18 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use containerd_shim_wasm::container::Instance;
+use anyhow::Context;
+use containerd_shim_wasm::container::{Instance, RuntimeContext};
+use containerd_shim_wasm::sandbox::Instance as _;
 use containerd_shim_wasm::testing::modules::*;
-use containerd_shim_wasm::testing::{oci_helpers, WasiTest};
+use containerd_shim_wasm::testing::{oci_helpers, wasm_layer, WasiTest};
+use oci_spec::image::{Arch, Platform, PlatformBuilder};
 use serial_test::serial;
 use wasmtime::Config;
 use WasmtimeTestInstance as WasiInstance;
 
-use crate::instance::{WasiConfig, WasmtimeEngine};
+use crate::host::WASI_CONFIG_LAYER_MEDIA_TYPE;
+use crate::instance::{HostInterface, WasiConfig, WasiCtx, WasmtimeEngine};
 
 // use test configuration to avoid dead locks when running tests
 // https://github.com/containerd/runwasi/issues/357
@@ -27,42 +32,7 @@ impl WasiConfig for WasiTestConfig {
     }
 }
 
-#[test]
-#[serial]
-fn test_delete_after_create() -> anyhow::Result<()> {
-    WasiTest::<WasiInstance>::builder()?.build()?.delete()?;
-    Ok(())
-}
-
-#[test]
-#[serial]
-fn test_hello_world() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HELLO_WORLD)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
-
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "hello world\n");
-
-    Ok(())
-}
-
-#[test]
-#[serial]
-fn test_hello_world_oci() -> anyhow::Result<()> {
-    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HELLO_WORLD)?
-        .as_oci_image(None, None)?;
-
-    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
-
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "hello world\n");
-
-    Ok(())
-}
+containerd_shim_wasm::conformance_tests!(WasiInstance);
 
 #[test]
 #[serial]
@@ -142,118 +112,258 @@ fn test_hello_world_oci_uses_precompiled_when_content_removed() -> anyhow::Resul
     Ok(())
 }
 
+// Test that the shim can execute an named exported function
+// that is not the default _start function in a wasm component.
+// The current limitation is that there is no way to pass arguments
+// to the exported function.
+// Issue that tracks this: https://github.com/containerd/runwasi/issues/414
 #[test]
 #[serial]
-fn test_custom_entrypoint() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_start_fn("foo")?
-        .with_wasm(CUSTOM_ENTRYPOINT)?
+fn test_simple_component() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SIMPLE_COMPONENT)?
+        .with_start_fn("thunk")?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "hello world\n");
 
     Ok(())
 }
 
+// Test that the shim can execute a wasm component that is
+// compiled with wasip2.
+//
+// This is using the `wasi:cli/command` world to run the component.
+//
+// The wasm component is built and copied over from
+// https://github.com/Mossaka/wasm-component-hello-world. See
+// README.md for how to build the component.
 #[test]
 #[serial]
-fn test_unreachable() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(UNREACHABLE)?
+fn test_wasip2_component() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COMPONENT_HELLO_WORLD)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
-    assert_ne!(exit_code, 0);
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "Hello, world!\n");
 
     Ok(())
 }
 
+fn wasip1_platform() -> anyhow::Result<Platform> {
+    Ok(PlatformBuilder::default()
+        .os("wasip1")
+        .architecture(Arch::Wasm)
+        .build()?)
+}
+
+// Test that the config layers of an image are not run as the module.
 #[test]
 #[serial]
-fn test_exit_code() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(EXIT_CODE)?
+fn test_oci_layers_with_config_layer() -> anyhow::Result<()> {
+    let layers = [
+        wasm_layer(WASI_CONFIG_LAYER_MEDIA_TYPE, r#"{"greeting":"hello"}"#)?,
+        wasm_layer("application/wasm", HELLO_WORLD)?,
+    ];
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, wasip1_platform()?)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
-    assert_eq!(exit_code, 42);
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
 
     Ok(())
 }
 
+// Test that components are run from images that declare the wasip1 platform,
+// as images built before wasip2 existed declare it for any wasm binary.
 #[test]
 #[serial]
-fn test_seccomp() -> anyhow::Result<()> {
+fn test_oci_layers_with_component_in_wasip1_image() -> anyhow::Result<()> {
+    let layers = [wasm_layer(
+        "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
+        COMPONENT_HELLO_WORLD,
+    )?];
     let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SECCOMP)?
+        .with_wasm_layers(layers, wasip1_platform()?)?
         .build()?
         .start()?
         .wait(Duration::from_secs(10))?;
 
     assert_eq!(exit_code, 0);
-    assert_eq!(stdout.trim(), "current working dir: /");
+    assert_eq!(stdout, "Hello, world!\n");
 
     Ok(())
 }
 
+// Test that components of wasm OCI artifacts that declare the wasip1 platform
+// are rejected when the container is created.
 #[test]
 #[serial]
-fn test_has_default_devices() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(HAS_DEFAULT_DEVICES)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_oci_artifact_with_component_in_wasip1_image() -> anyhow::Result<()> {
+    let layers = [wasm_layer("application/wasm", COMPONENT_HELLO_WORLD)?];
+    let err = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, wasip1_platform()?)?
+        .build()
+        .err()
+        .expect("the component requires wasip2");
 
-    assert_eq!(exit_code, 0);
+    assert!(format!("{err:#}").contains("requires wasip2"), "{err:#}");
 
     Ok(())
 }
 
-// Test that the shim can execute an named exported function
-// that is not the default _start function in a wasm component.
-// The current limitation is that there is no way to pass arguments
-// to the exported function.
-// Issue that tracks this: https://github.com/containerd/runwasi/issues/414
+// Test that a shim that reattaches to a container, e.g. after a restart, reports the exit code
+// of its module, which it can't collect from a process that is not its child.
 #[test]
 #[serial]
-fn test_simple_component() -> anyhow::Result<()> {
-    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(SIMPLE_COMPONENT)?
+fn test_reattached_container_exit_code() -> anyhow::Result<()> {
+    let layers = [wasm_layer("application/wasm", EXIT_CODE)?];
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, wasip1_platform()?)?
+        .build()?;
+    test.start()?;
+
+    let (exit_code, _) = test
+        .reattach()?
+        .wait_timeout(Duration::from_secs(10))
+        .context("timeout while waiting for the reattached container")?;
+    assert_eq!(exit_code, 42);
+
+    let (exit_code, _, _) = test.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
+
+    Ok(())
+}
+
+// Test that images with several modules are rejected when the container is created.
+#[test]
+#[serial]
+fn test_oci_layers_with_several_modules() -> anyhow::Result<()> {
+    let layers = [
+        wasm_layer("application/wasm", HELLO_WORLD)?,
+        wasm_layer("application/wasm", EXIT_CODE)?,
+    ];
+    let result = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, wasip1_platform()?)?
+        .build();
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+// Test that components the shim can't run are rejected when the container is created,
+// instead of failing when it's started.
+#[test]
+#[serial]
+fn test_component_world_is_checked_on_create() -> anyhow::Result<()> {
+    let platform = PlatformBuilder::default()
+        .os("wasip2")
+        .architecture(Arch::Wasm)
+        .build()?;
+
+    // imports an interface that the shim doesn't provide
+    let component = wat::parse_str(
+        r#"(component
+            (import "wasi:unknown/iface@0.1.0" (instance (export "f" (func))))
+            (core module $m (func (export "thunk")))
+            (core instance $i (instantiate $m))
+            (func (export "thunk") (canon lift (core func $i "thunk")))
+        )"#,
+    )?;
+    let layers = [wasm_layer("application/wasm", component)?];
+    let err = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, platform.clone())?
         .with_start_fn("thunk")?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+        .build()
+        .err()
+        .expect("the container should not be created");
+    assert!(
+        format!("{err:#}")
+            .contains("component imports interfaces that are not provided by the shim"),
+        "{err:#}"
+    );
 
-    assert_eq!(exit_code, 0);
+    // doesn't export `wasi:cli/run` to run as a command
+    let layers = [wasm_layer("application/wasm", SIMPLE_COMPONENT)?];
+    let err = WasiTest::<WasiInstance>::builder()?
+        .with_wasm_layers(layers, platform)?
+        .build()
+        .err()
+        .expect("the container should not be created");
+    assert!(
+        format!("{err:#}").contains("component doesn't target the `wasi:cli/command` world"),
+        "{err:#}"
+    );
 
     Ok(())
 }
 
-// Test that the shim can execute a wasm component that is
-// compiled with wasip2.
-//
-// This is using the `wasi:cli/command` world to run the component.
-//
-// The wasm component is built and copied over from
-// https://github.com/Mossaka/wasm-component-hello-world. See
-// README.md for how to build the component.
+#[derive(Clone)]
+struct WasiTestConfigWithHost {}
+
+impl WasiConfig for WasiTestConfigWithHost {
+    fn new_config() -> Config {
+        WasiTestConfig::new_config()
+    }
+
+    fn host_interfaces() -> Vec<Arc<dyn HostInterface>> {
+        vec![Arc::new(AnswerInterface)]
+    }
+}
+
+struct Answer(i32);
+
+struct AnswerInterface;
+
+impl HostInterface for AnswerInterface {
+    fn prepare_context(
+        &self,
+        _ctx: &dyn RuntimeContext,
+        wasi_ctx: &mut WasiCtx,
+    ) -> anyhow::Result<()> {
+        wasi_ctx.insert_extension(Answer(42));
+        Ok(())
+    }
+
+    fn add_to_linker(&self, linker: &mut wasmtime::Linker<WasiCtx>) -> anyhow::Result<()> {
+        linker.func_wrap("test", "answer", |caller: wasmtime::Caller<'_, WasiCtx>| {
+            caller
+                .data()
+                .extension::<Answer>()
+                .map_or(0, |Answer(answer)| *answer)
+        })?;
+        Ok(())
+    }
+}
+
+// Test that the host interfaces of the engine configuration are available to the modules.
 #[test]
 #[serial]
-fn test_wasip2_component() -> anyhow::Result<()> {
-    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
-        .with_wasm(COMPONENT_HELLO_WORLD)?
-        .build()?
-        .start()?
-        .wait(Duration::from_secs(10))?;
+fn test_host_interfaces() -> anyhow::Result<()> {
+    let module = wat::parse_str(
+        r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (import "test" "answer" (func $answer (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (call $exit (call $answer))))"#,
+    )?;
+
+    let (exit_code, _, _) =
+        WasiTest::<Instance<WasmtimeEngine<WasiTestConfigWithHost>>>::builder()?
+            .with_wasm(module)?
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
 
-    assert_eq!(exit_code, 0);
-    assert_eq!(stdout, "Hello, world!\n");
+    assert_eq!(exit_code, 42);
 
     Ok(())
 }
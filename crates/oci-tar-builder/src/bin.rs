@@ -6,7 +6,10 @@ use std::{env, fs};
 use anyhow::Context;
 use clap::Parser;
 use oci_spec::image::{self as spec, Arch};
-use oci_tar_builder::{Builder, WASM_LAYER_MEDIA_TYPE};
+use oci_tar_builder::{
+    Builder, WasmConfig, WASM_ARTIFACT_LAYER_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+    WEBC_LAYER_MEDIA_TYPE,
+};
 use sha256::{digest, try_digest};
 
 pub fn main() {
@@ -20,13 +23,26 @@ pub fn main() {
         out_dir = env::current_dir().unwrap();
     }
 
-    let entry_point = args.name.clone() + ".wasm";
+    // the entrypoint of a webc package selects its command with `#<command>`,
+    // without it the entrypoint command of the package runs
+    let entry_point = match (&args.webc, &args.command) {
+        (Some(_), Some(command)) => format!("{}.webc#{command}", args.name),
+        (Some(_), None) => args.name.clone() + ".webc",
+        (None, _) => args.name.clone() + ".wasm",
+    };
+
+    // wasm OCI artifacts use a different media type for the wasm layers
+    let wasm_layer_media_type = if args.artifact {
+        WASM_ARTIFACT_LAYER_MEDIA_TYPE
+    } else {
+        WASM_LAYER_MEDIA_TYPE
+    };
 
     let mut builder = Builder::default();
     let mut layer_digests = Vec::new();
     for module_path in args.module.iter() {
         let module_path = PathBuf::from(module_path);
-        builder.add_layer_with_media_type(&module_path, WASM_LAYER_MEDIA_TYPE.to_string());
+        builder.add_layer_with_media_type(&module_path, wasm_layer_media_type.to_string());
         layer_digests.push(
             try_digest(&module_path)
                 .context("failed to calculate digest for module")
@@ -34,6 +50,16 @@ pub fn main() {
         );
     }
 
+    if let Some(webc_path) = args.webc.as_deref() {
+        let webc_path = PathBuf::from(webc_path);
+        builder.add_layer_with_media_type(&webc_path, WEBC_LAYER_MEDIA_TYPE.to_string());
+        layer_digests.push(
+            try_digest(&webc_path)
+                .context("failed to calculate digest for webc package")
+                .unwrap(),
+        );
+    }
+
     for layer_config in args.layer.iter() {
         //split string on equals sign
         let layer_options: Vec<&str> = layer_config.split('=').collect();
@@ -56,7 +82,7 @@ pub fn main() {
             let ext = path.extension().unwrap().to_str().unwrap();
             match ext {
                 "wasm" => {
-                    builder.add_layer_with_media_type(&path, WASM_LAYER_MEDIA_TYPE.to_string());
+                    builder.add_layer_with_media_type(&path, wasm_layer_media_type.to_string());
                     layer_digests.push(
                         try_digest(&path)
                             .context("failed to calculate digest for module")
@@ -72,6 +98,35 @@ pub fn main() {
         }
     }
 
+    let name = args.repo + "/" + &args.name + ":" + &args.tag;
+    if args.artifact {
+        builder.add_wasm_config(WasmConfig::new(args.os.as_str()), name);
+    } else {
+        add_image_config(&mut builder, name, entry_point, &args.os, layer_digests);
+    }
+
+    println!("Creating oci tar file {}", out_dir.clone().display());
+    let f = File::create(out_dir.clone()).unwrap();
+    match builder.build(f) {
+        Ok(_) => println!("Successfully created oci tar file {}", out_dir.display()),
+        Err(e) => {
+            print!(
+                "Building oci tar file {} failed: {:?}",
+                out_dir.display(),
+                e
+            );
+            fs::remove_file(out_dir).unwrap_or(print!("Failed to remove temporary file"));
+        }
+    }
+}
+
+fn add_image_config(
+    builder: &mut Builder,
+    name: String,
+    entry_point: String,
+    os: &str,
+    layer_digests: Vec<String>,
+) {
     // Need each config to be unique since we don't have layers to make them unique in the rootfs
     // https://github.com/opencontainers/image-spec/pull/1173
     let unique_id = digest(layer_digests.join(""));
@@ -85,7 +140,7 @@ pub fn main() {
 
     let img = spec::ImageConfigurationBuilder::default()
         .config(config)
-        .os("wasip1")
+        .os(os)
         .architecture(Arch::Wasm)
         .rootfs(
             spec::RootFsBuilder::default()
@@ -97,21 +152,7 @@ pub fn main() {
         .context("failed to build image configuration")
         .unwrap();
 
-    builder.add_config(img, args.repo + "/" + &args.name + ":" + &args.tag);
-
-    println!("Creating oci tar file {}", out_dir.clone().display());
-    let f = File::create(out_dir.clone()).unwrap();
-    match builder.build(f) {
-        Ok(_) => println!("Successfully created oci tar file {}", out_dir.display()),
-        Err(e) => {
-            print!(
-                "Building oci tar file {} failed: {:?}",
-                out_dir.display(),
-                e
-            );
-            fs::remove_file(out_dir).unwrap_or(print!("Failed to remove temporary file"));
-        }
-    }
+    builder.add_config(img, name);
 }
 
 #[derive(Parser, Debug)]
@@ -137,4 +178,21 @@ struct Args {
 
     #[arg(short, long)]
     components: Option<String>,
+
+    /// A webc package to add as an `application/webc` layer, for the wasmer shim
+    #[arg(long)]
+    webc: Option<String>,
+
+    /// The command of the webc package to run, instead of its entrypoint
+    #[arg(long, requires = "webc")]
+    command: Option<String>,
+
+    /// The wasm platform of the image, e.g. wasip1 or wasip2
+    #[arg(long, default_value = "wasip1")]
+    os: String,
+
+    /// Build a wasm OCI artifact (application/vnd.wasm.config.v0+json config and application/wasm layers)
+    /// instead of an image
+    #[arg(long)]
+    artifact: bool,
 }
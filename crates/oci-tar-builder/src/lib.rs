@@ -7,17 +7,65 @@ use anyhow::{Context, Error, Result};
 use indexmap::IndexMap;
 use log::{debug, warn};
 use oci_spec::image::{
-    DescriptorBuilder, ImageConfiguration, ImageIndexBuilder, ImageManifestBuilder, MediaType,
-    PlatformBuilder, SCHEMA_VERSION,
+    Arch, DescriptorBuilder, ImageConfiguration, ImageIndexBuilder, ImageManifestBuilder,
+    MediaType, Os, PlatformBuilder, SCHEMA_VERSION,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha256::{digest, try_digest};
 #[derive(Debug, Default)]
 pub struct Builder {
-    configs: Vec<(ImageConfiguration, String)>,
+    configs: Vec<(Config, String)>,
     layers: Vec<(PathBuf, String)>,
 }
 
+#[derive(Debug)]
+enum Config {
+    Image(Box<ImageConfiguration>),
+    Wasm(WasmConfig),
+}
+
+/// The config of a wasm OCI artifact, as defined by the CNCF TAG Runtime
+/// <https://tag-runtime.cncf.io/wgs/wasm/deliverables/wasm-oci-artifact/>.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WasmConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub architecture: Arch,
+    pub os: Os,
+    /// The digests of the layers of the artifact, filled in by [`Builder::build`]
+    #[serde(default)]
+    pub layer_digests: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component: Option<WasmComponent>,
+}
+
+/// The imports and exports of a component in a [`WasmConfig`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WasmComponent {
+    #[serde(default)]
+    pub exports: Vec<String>,
+    #[serde(default)]
+    pub imports: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl WasmConfig {
+    pub fn new(os: impl Into<Os>) -> Self {
+        Self {
+            created: None,
+            author: None,
+            architecture: Arch::Wasm,
+            os: os.into(),
+            layer_digests: vec![],
+            component: None,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct OciLayout {
     #[serde(rename = "imageLayoutVersion")]
@@ -45,9 +93,25 @@ struct DockerManifest {
 pub const WASM_LAYER_MEDIA_TYPE: &str =
     "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm";
 
+/// Media type of the config of a wasm OCI artifact.
+pub const WASM_ARTIFACT_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v0+json";
+
+/// Media type of the layers of a wasm OCI artifact.
+pub const WASM_ARTIFACT_LAYER_MEDIA_TYPE: &str = "application/wasm";
+
+/// Media type of the layers with a [webc](https://docs.wasmer.io/registry/webc) package.
+pub const WEBC_LAYER_MEDIA_TYPE: &str = "application/webc";
+
 impl Builder {
     pub fn add_config(&mut self, config: ImageConfiguration, name: String) -> &mut Self {
-        self.configs.push((config, name));
+        self.configs.push((Config::Image(Box::new(config)), name));
+        self
+    }
+
+    /// Adds the config of a wasm OCI artifact, see [`WasmConfig`].
+    /// The layers of an artifact should have the [`WASM_ARTIFACT_LAYER_MEDIA_TYPE`] media type.
+    pub fn add_wasm_config(&mut self, config: WasmConfig, name: String) -> &mut Self {
+        self.configs.push((Config::Wasm(config), name));
         self
     }
 
@@ -108,7 +172,26 @@ impl Builder {
         }
 
         for config in self.configs.iter() {
-            let s = config.0.to_string().context("failed to serialize config")?;
+            let (s, media_type, os, architecture) = match &config.0 {
+                Config::Image(image) => (
+                    image.to_string().context("failed to serialize config")?,
+                    MediaType::ImageConfig,
+                    image.os().clone(),
+                    image.architecture().clone(),
+                ),
+                Config::Wasm(wasm) => {
+                    let wasm = WasmConfig {
+                        layer_digests: layer_digests.keys().cloned().collect(),
+                        ..wasm.clone()
+                    };
+                    (
+                        serde_json::to_string(&wasm).context("failed to serialize config")?,
+                        MediaType::Other(WASM_ARTIFACT_CONFIG_MEDIA_TYPE.to_string()),
+                        wasm.os,
+                        wasm.architecture,
+                    )
+                }
+            };
             let b = s.as_bytes();
             let dgst = digest(b);
             let mut th = tar::Header::new_gnu();
@@ -122,7 +205,7 @@ impl Builder {
             mfst.config = p.to_string();
 
             let desc = DescriptorBuilder::default()
-                .media_type(MediaType::ImageConfig)
+                .media_type(media_type.clone())
                 .size(b.len() as i64)
                 .digest("sha256:".to_owned() + &dgst)
                 .build()
@@ -134,10 +217,12 @@ impl Builder {
                 layers.push(v.clone());
             }
 
-            for id in config.0.rootfs().diff_ids().iter() {
-                debug!("id: {}", id);
-                if layer_digests.get(id).is_none() {
-                    warn!("rootfs diff with id {} not found in layers", id);
+            if let Config::Image(image) = &config.0 {
+                for id in image.rootfs().diff_ids().iter() {
+                    debug!("id: {}", id);
+                    if layer_digests.get(id).is_none() {
+                        warn!("rootfs diff with id {} not found in layers", id);
+                    }
                 }
             }
 
@@ -152,9 +237,12 @@ impl Builder {
             mfst.repo_tags.push(config.1.clone());
             annotations.insert("io.containerd.image.name".to_string(), config.1.clone());
 
-            let manifest = ImageManifestBuilder::default()
+            let mut manifest = ImageManifestBuilder::default()
                 .schema_version(SCHEMA_VERSION)
                 .media_type(MediaType::ImageManifest);
+            if let Config::Wasm(_) = config.0 {
+                manifest = manifest.artifact_type(media_type);
+            }
 
             let manifest = manifest
                 .layers(layers)
@@ -176,8 +264,8 @@ impl Builder {
             tb.append(&th, b)?;
 
             let platform = PlatformBuilder::default()
-                .os(config.0.os().clone())
-                .architecture(config.0.architecture().clone())
+                .os(os)
+                .architecture(architecture)
                 .build()
                 .context("failed to build platform")?;
 